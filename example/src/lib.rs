@@ -1,11 +1,13 @@
-#![feature(plugin, custom_attribute, const_fn, placement_in_syntax)]
-#![plugin(rustyphp_plugin)]
-
 #[macro_use]
 extern crate rustyphp;
 
 use rustyphp::*;
 
+/// Route all Rust allocations through the Zend memory manager (opt-in: `--features zend_alloc`)
+#[cfg(feature = "zend_alloc")]
+#[global_allocator]
+static ALLOC: ZendAllocator = ZendAllocator;
+
 /// Sample PHP function printing hello world, replace by macro
 /// void zif_hello_world(zend_execute_data *execute_data, zval *return_value)
 #[php_func]
@@ -34,18 +36,22 @@ macro_rules! php_test {
             use std::path::Path;
             use std::process::Command;
 
+            use ::rustyphp::testing::LeakCheck;
+
             struct Settings<'a> {
                 check_func: Box<Fn(&str, &str, &str)>,
                 code: Option<&'a str>,
                 status_success: bool,
-                expect: Option<&'a str>
+                expect: Option<&'a str>,
+                leak_check: bool
             }
 
             let mut settings = Settings {
                 check_func: Box::new(|expect: &str, stdout: &str, _| assert!(stdout.trim() == expect, "EXPECTED:\n{}\nGOT:\n{}\n", expect, stdout.trim())),
                 code: None,
                 status_success: true,
-                expect: None
+                expect: None,
+                leak_check: false
             };
             $(
                 settings.$k = php_test_helper!($k, $v);
@@ -53,7 +59,11 @@ macro_rules! php_test {
 
             let target_path = Path::new(env!("CARGO_MANIFEST_DIR")).join("target/debug/testext.dll"); //TODO path
             println!("{}", target_path.display());
-            let output = Command::new(::rustyphp::testing::PHP_PATH)
+            let leak_check = match settings.leak_check {
+                true => LeakCheck::detect(),
+                false => LeakCheck::Off
+            };
+            let output = ::rustyphp::testing::php_command(leak_check)
                 .arg(format!("-dextension=\"{}\"", target_path.display()))
                 .args(&["-r", settings.code.unwrap()])
                 .output()
@@ -64,14 +74,19 @@ macro_rules! php_test {
             if settings.expect.is_some() {
                 (settings.check_func)(settings.expect.unwrap(), &stdout, &stderr)
             }
+            ::rustyphp::testing::assert_no_leaks(leak_check, &stderr);
         }
     }
 }
 php_test!(test_hello_world, code => "var_dump(hello_world);", expect => "string(11) \"hello_world\"");
 mod test_funcs;
 mod test_objs;
+#[cfg(feature = "zend_alloc")]
+mod test_alloc;
+mod test_exceptions;
+mod test_errors;
 
-// This has to be last (else it throws an compiler error "`php_func` cannot be used outside an extension" for test funcs)
+// Functions and classes are collected when the module is loaded, so this can go anywhere
 php_ext!(
     name => "test_ext".as_ptr()
     version => "0.0.1".as_ptr()
@@ -0,0 +1,23 @@
+use rustyphp::*;
+
+/// Allocations done by Rust have to be visible in memory_get_usage()
+#[php_func]
+fn rustyphp_alloc_accounted(size: i64) -> bool {
+    let before = memory_usage();
+    let buf: Vec<u8> = Vec::with_capacity(size as usize);
+    let after = memory_usage();
+    drop(buf);
+    after - before >= size as usize && memory_usage() == before
+}
+php_test!(alloc_accounted, code => "var_dump(rustyphp_alloc_accounted(1 << 20));", expect => "bool(true)");
+
+/// memory_limit fails the allocation instead of bailing out through the Rust frames
+#[php_func]
+fn rustyphp_alloc_try(size: i64) -> bool {
+    let mut buf: Vec<u8> = Vec::new();
+    buf.try_reserve(size as usize).is_ok()
+}
+php_test!(alloc_memory_limit,
+    code => "ini_set('memory_limit', '16M'); var_dump(rustyphp_alloc_try(32 << 20), rustyphp_alloc_try(1 << 20));",
+    expect => "bool(false)\nbool(true)"
+);
@@ -0,0 +1,50 @@
+use rustyphp::*;
+
+/// Warn and return false like the built-in functions do
+#[php_func]
+fn rustyphp_warn(path: String) -> bool {
+    if path.is_empty() {
+        php_warning!("Path cannot be {}", "empty");
+        return false
+    }
+    true
+}
+php_test!(error_warning,
+    code => "var_dump(rustyphp_warn(''));",
+    expect => "Warning: rustyphp_warn(): Path cannot be empty in Command line code on line 1\nbool(false)",
+    check_func => |expect: &str, stdout: &str, _| assert!(stdout.trim().ends_with(expect), "EXPECTED:\n{}\nGOT:\n{}\n", expect, stdout.trim())
+);
+php_test!(error_warning_silenced,
+    code => "var_dump(@rustyphp_warn('')); echo error_get_last()['message'];",
+    expect => "bool(false)\nrustyphp_warn(): Path cannot be empty"
+);
+php_test!(error_warning_handler,
+    code => "set_error_handler(function ($no, $str) { echo $no === E_WARNING ? 'W:' : '?:', $str, '|'; }); rustyphp_warn('');",
+    expect => "W:rustyphp_warn(): Path cannot be empty|"
+);
+
+#[php_func]
+fn rustyphp_notice_deprecated() {
+    php_notice!("notice {}", 1);
+    php_deprecated!("deprecated {}", 2);
+}
+php_test!(error_notice_deprecated,
+    code => "set_error_handler(function ($no, $str) { echo $no, ':', $str, '|'; }); rustyphp_notice_deprecated();",
+    expect => "8:rustyphp_notice_deprecated(): notice 1|8192:rustyphp_notice_deprecated(): deprecated 2|"
+);
+php_test!(error_reporting_respected,
+    code => "error_reporting(E_ALL & ~E_NOTICE & ~E_DEPRECATED); rustyphp_notice_deprecated(); echo 'done';",
+    expect => "done"
+);
+
+/// php_error! drops the Rust values before aborting the request
+#[php_func]
+fn rustyphp_fatal(msg: String) {
+    let _buf = vec![0u8; 1024];
+    php_error!("fatal: {}", msg);
+}
+php_test!(error_fatal, status_success => false,
+    code => "rustyphp_fatal('boom'); echo 'unreachable';",
+    expect => "Fatal error: rustyphp_fatal(): fatal: boom",
+    check_func => |expect: &str, stdout: &str, _| assert!(stdout.trim().starts_with(expect) && !stdout.contains("unreachable"), "EXPECTED:\n{}\nGOT:\n{}\n", expect, stdout.trim())
+);
@@ -0,0 +1,45 @@
+use rustyphp::*;
+
+/// Exceptions raised by the engine while running `try_catch` are handed back as `Err`
+#[php_func]
+fn rustyphp_catch_exception(msg: String) -> String {
+    let ret = try_catch(|| {
+        PhpException::new(msg).class(ExceptionClass::RuntimeException).code(3).throw();
+    });
+    match ret {
+        Ok(()) => "no exception".to_owned(),
+        Err(ex) => format!("{}|{}", ex, ex.code)
+    }
+}
+php_test!(catch_exception,
+    code => "echo rustyphp_catch_exception('caught in rust');",
+    expect => "RuntimeException: caught in rust|3"
+);
+
+/// A caught exception can be thrown again, the original object is rethrown
+#[php_func]
+fn rustyphp_catch_rethrow(msg: String) {
+    if let Err(ex) = try_catch(|| PhpException::new(msg).class(ExceptionClass::LogicException).throw()) {
+        ex.throw();
+    }
+}
+php_test!(catch_rethrow,
+    code => "try { rustyphp_catch_rethrow('again'); } catch (LogicException $e) { echo get_class($e), '|', $e->getMessage(); }",
+    expect => "LogicException|again"
+);
+
+/// A fatal error inside `try_catch` is reported as bailout and re-raised after the function returned
+#[php_func]
+fn rustyphp_catch_bailout() -> bool {
+    let ret = try_catch(|| unsafe {
+        ffi::zend_error(ffi::E_ERROR, b"%s\0".as_ptr() as *const _, b"fatal in callback\0".as_ptr());
+    });
+    let bailout = ret.err().map(|ex| ex.bailout).unwrap_or(false);
+    println!("RUST_BAILOUT({})", bailout);
+    bailout
+}
+php_test!(catch_bailout, status_success => false,
+    code => "rustyphp_catch_bailout(); echo 'unreachable';",
+    expect => "Fatal error: fatal in callback",
+    check_func => |expect: &str, stdout: &str, _| assert!(stdout.contains(expect) && stdout.contains("RUST_BAILOUT(true)") && !stdout.contains("unreachable"), "EXPECTED:\n{}\nGOT:\n{}\n", expect, stdout.trim())
+);
@@ -0,0 +1,41 @@
+use std::fmt;
+use rustyphp::*;
+
+/// Every variant is thrown as `RustyPhp\RustyException` unless it declares an own class
+#[php_exception(name = "RustyPhp\\RustyException", extends = "RuntimeException")]
+#[derive(Debug)]
+pub enum RustyError {
+    #[php_exception(name = "RustyPhp\\ConnectionException")]
+    Connection(String),
+    Timeout { secs: u32 }
+}
+
+impl fmt::Display for RustyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RustyError::Connection(ref reason) => write!(f, "connection {}", reason),
+            RustyError::Timeout { secs } => write!(f, "timeout after {}s", secs)
+        }
+    }
+}
+
+#[php_func]
+fn rustyphp_throw_custom(kind: i64) {
+    let res: Result<(), RustyError> = match kind {
+        0 => Err(RustyError::Connection("refused".to_owned())),
+        _ => Err(RustyError::Timeout { secs: 3 })
+    };
+    zend_try!(res);
+}
+php_test!(throw_custom_variant,
+    code => "try { rustyphp_throw_custom(0); } catch (RustyPhp\\RustyException $e) { echo get_class($e), '|', $e->getMessage(), '|', ($e instanceof RuntimeException ? 'Y' : 'N'); }",
+    expect => "RustyPhp\\ConnectionException|connection refused|Y"
+);
+php_test!(throw_custom_base,
+    code => "try { rustyphp_throw_custom(1); } catch (RuntimeException $e) { echo get_class($e), '|', $e->getMessage(); }",
+    expect => "RustyPhp\\RustyException|timeout after 3s"
+);
+php_test!(throw_custom_from_php,
+    code => "try { throw new RustyPhp\\ConnectionException('from php'); } catch (RustyPhp\\RustyException $e) { echo get_class($e), '|', $e->getMessage(), '|', get_parent_class($e); }",
+    expect => "RustyPhp\\ConnectionException|from php|RustyPhp\\RustyException"
+);
@@ -0,0 +1,4 @@
+pub mod throw;
+pub mod custom;
+pub mod panic;
+pub mod catch;
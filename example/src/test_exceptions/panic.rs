@@ -0,0 +1,11 @@
+use rustyphp::php_func;
+
+/// Panics are converted into `Error` exceptions instead of unwinding into the engine
+#[php_func]
+fn rustyphp_panic(msg: String) {
+    panic!("{}", msg);
+}
+php_test!(panic_to_error,
+    code => "for ($i = 0; $i < 2; ++$i) { try { rustyphp_panic('rust boom'); } catch (Error $e) { echo strstr($e->getMessage(), ', src', true), '|'; } }",
+    expect => "rustyphp_panic(): panicked at 'rust boom'|rustyphp_panic(): panicked at 'rust boom'|"
+);
@@ -0,0 +1,29 @@
+use rustyphp::*;
+
+#[php_func]
+fn rustyphp_throw_typed(code: i64) {
+    let ex = PhpException::new("bad value\0here")
+        .class(ExceptionClass::InvalidArgumentException)
+        .code(code)
+        .previous(PhpException::new("inner").class(ExceptionClass::RuntimeException));
+    throw_exception!(ex);
+}
+php_test!(throw_typed,
+    code => "try { rustyphp_throw_typed(42); } catch (InvalidArgumentException $e) { echo get_class($e), '|', $e->getCode(), '|', str_replace(\"\\0\", '<NUL>', $e->getMessage()), '|', get_class($e->getPrevious()); }",
+    expect => "InvalidArgumentException|42|bad value<NUL>here|RuntimeException"
+);
+
+/// Classes which can't be thrown fall back to Exception, lookups never run the autoloader
+#[php_func]
+fn rustyphp_throw_named(class: String) {
+    throw_exception!(PhpException::new("named").class(ExceptionClass::Named(class)));
+}
+php_test!(throw_named,
+    code => "spl_autoload_register(function ($c) { echo 'AUTOLOAD(', $c, ')'; });
+        abstract class AbstractEx extends Exception {}
+        class MyEx extends Exception {}
+        foreach (['\\\\MyEx', 'stdClass', 'Throwable', 'AbstractEx', 'Missing'] as $c) {
+            try { rustyphp_throw_named($c); } catch (Exception $e) { echo get_class($e), ','; }
+        }",
+    expect => "MyEx,Exception,Exception,Exception,Exception,"
+);
@@ -0,0 +1,21 @@
+use rustyphp::php_func;
+
+#[php_func(name = "rustyphp_func_str_distance", alias = "rustyphp_func_strdist", deprecated_alias = "rustyphp_func_old_distance")]
+fn distance(p1: String, p2: String) -> i64 {
+    (p1.len() as i64 - p2.len() as i64).abs()
+}
+php_test!(alias,
+    code => "echo rustyphp_func_str_distance('a', 'abc'), rustyphp_func_strdist('ab', 'a'), '|', function_exists('distance') ? 'Y' : 'N';",
+    expect => "21|N"
+);
+php_test!(alias_deprecated,
+    code => "set_error_handler(function ($no, $str) { echo $no === E_DEPRECATED ? 'D:' : '?:', $str, '|'; }); echo rustyphp_func_old_distance('', 'abc');",
+    expect => "D:Function rustyphp_func_old_distance() is deprecated|3"
+);
+
+/// The same Rust name as in `ret`, the generated symbols must not collide
+#[php_func(name = "rustyphp_func_alias_ret_u32")]
+fn rustyphp_func_ret_u32() -> u32 {
+    7
+}
+php_test!(alias_same_rust_name, code => "echo rustyphp_func_ret_u32(), rustyphp_func_alias_ret_u32();", expect => "427");
@@ -1,4 +1,5 @@
-use rustyphp::{ZendArray, ZvalValueObject};
+use rustyphp::{php_func, ByRef, Variadic, ZendArray, ZvalValueObject};
+use rustyphp::types::zstr::ZendStr;
 
 #[php_func]
 fn rustyphp_func_arg_i32(p1: i32) {
@@ -8,8 +9,8 @@ php_test!(i32, code => "rustyphp_func_arg_i32(42);", expect => "RUST_PRINTLN(42)
 
 #[php_func]
 fn rustyphp_func_arg_arr(p1: &mut ZendArray) {
-    let val1: &str = p1.get(42).unwrap();
-    let val2: &str = p1.get(666).unwrap();
+    let val1 = p1.get::<&str>(42).unwrap();
+    let val2 = p1.get::<&str>(666).unwrap();
     println!("RS_ARR[42]={}\nRS_ARR[666]={}", val1, val2);
     match p1.get::<i32>(0) {
         Err(_) => println!("RUST_OK"),
@@ -19,6 +20,18 @@ fn rustyphp_func_arg_arr(p1: &mut ZendArray) {
 
 php_test!(arr, code => "rustyphp_func_arg_arr(array(42 => \"hell yeah\", 666 => \"devil\"));", expect => "RS_ARR[42]=hell yeah\nRS_ARR[666]=devil\nRUST_OK");
 
+/// Mutating the argument must separate it from the caller's (possibly immutable) array
+#[php_func]
+fn rustyphp_func_arg_arr_mut(p1: &mut ZendArray) {
+    zend_try_option!(p1.insert(0, "rust"));
+    zend_try_option!(p1.push(42));
+    println!("RS_ARR_LEN={}", p1.len());
+}
+php_test!(arr_separate, leak_check => true,
+    code => "$a = array(\"php\"); $b = $a; rustyphp_func_arg_arr_mut($a); rustyphp_func_arg_arr_mut(array(\"php\")); echo $a[0].$b[0].count($a);",
+    expect => "RS_ARR_LEN=2\nRS_ARR_LEN=2\nphpphp1"
+);
+
 #[php_func]
 fn rustyphp_func_arg_obj(p1: &mut ZvalValueObject) {
     match p1.read_property::<u32>("prop") {
@@ -28,6 +41,19 @@ fn rustyphp_func_arg_obj(p1: &mut ZvalValueObject) {
 }
 php_test!(obj, code => "$g=new stdClass();$g->prop=1;rustyphp_func_arg_obj($g);", expect => "RUST_PRINTLN(1)");
 
+/// Exceptions thrown by __get() are returned instead of unwinding through the Rust frames
+#[php_func]
+fn rustyphp_func_arg_obj_magic(p1: &mut ZvalValueObject) {
+    match p1.read_property::<u32>("prop") {
+        Err(err) => println!("RUST_ERR({})", err),
+        Ok(x) => println!("RUST_PRINTLN({})", x)
+    }
+}
+php_test!(obj_magic_throws,
+    code => "class Magic { function __get($name) { throw new RuntimeException(\"no $name\"); } } rustyphp_func_arg_obj_magic(new Magic); echo 'Y';",
+    expect => "RUST_ERR(RuntimeException: no prop)\nY"
+);
+
 #[php_func]
 fn rustyphp_func_arg_obj_write(p1: &mut ZvalValueObject) {
     p1.write_property("prop", "yep");
@@ -42,18 +68,155 @@ fn rustyphp_func_arg_obj_memsafety(p1: &mut ZvalValueObject) {
         Ok(x) => assert_eq!(x, 2)
     }
 }
-php_test!(obj_memsafety_read_prop,
-    code => "$g=new stdClass(); $g->prop = 2; $before = memory_get_usage(); for ($c = 0; $c < 100; ++$c) { rustyphp_func_arg_obj_memsafety($g); } \
-    $after=memory_get_usage(); $status = ($before==$after?'Y':'N'); echo $status.'('.$before.','.$after.')';",
-    expect => "Y(",
-    check_func => |expect: &str, stdout: &str, _| { stdout.starts_with(expect); }
+php_test!(obj_memsafety_read_prop, leak_check => true,
+    code => "$g=new stdClass(); $g->prop = 2; for ($c = 0; $c < 100; ++$c) { rustyphp_func_arg_obj_memsafety($g); } echo 'Y';",
+    expect => "Y"
 );
 
-//TODO: Mark function arguments as required, overwrite via Optional<Arg> which is a typedef
-//to Option<Arg> but can be detected in the AST :)
 php_test!(
     missing_arg_obj, status_success => false,
     code => "rustyphp_func_arg_obj();",
-    expect => "ERR: stdout: Fatal error: Uncaught Exception: rustyphp_func_arg_obj: expected 1 arguments got 0",
-    check_func => |expect: &str, stdout: &str, _| { stdout.starts_with(expect); }
+    expect => "rustyphp_func_arg_obj() expects exactly 1 argument, 0 given",
+    // ArgumentCountError since PHP 7.1, TypeError before
+    check_func => |expect: &str, stdout: &str, _| assert!(stdout.trim().starts_with("Fatal error: Uncaught ") && stdout.contains(expect), "EXPECTED:\n{}\nGOT:\n{}\n", expect, stdout.trim())
+);
+
+/// Trailing Options are optional, `#[default]` fills in omitted arguments
+#[php_func]
+fn rustyphp_func_arg_defaults(p1: i64, #[default = 10] p2: i64, #[default = "x"] p3: String, p4: Option<bool>) -> String {
+    format!("{}|{}|{}|{:?}", p1, p2, p3, p4)
+}
+php_test!(args_defaults,
+    code => "echo rustyphp_func_arg_defaults(1), ',', rustyphp_func_arg_defaults(1, 2), ',', rustyphp_func_arg_defaults(1, 2, 'y', true);",
+    expect => "1|10|x|None,1|2|x|None,1|2|y|Some(true)"
+);
+php_test!(args_defaults_required, status_success => false,
+    code => "rustyphp_func_arg_defaults();",
+    expect => "rustyphp_func_arg_defaults() expects at least 1 argument, 0 given",
+    // ArgumentCountError since PHP 7.1, TypeError before
+    check_func => |expect: &str, stdout: &str, _| assert!(stdout.trim().starts_with("Fatal error: Uncaught ") && stdout.contains(expect), "EXPECTED:\n{}\nGOT:\n{}\n", expect, stdout.trim())
+);
+php_test!(args_defaults_too_many,
+    code => "try { rustyphp_func_arg_defaults(1, 2, 'y', true, 5); } catch (TypeError $e) { echo $e->getMessage(); }",
+    expect => "rustyphp_func_arg_defaults() expects at most 4 arguments, 5 given"
+);
+php_test!(args_count_error_class,
+    code => "try { rustyphp_func_arg_defaults(); } catch (TypeError $e) { echo get_class($e) === (PHP_VERSION_ID >= 70100 ? 'ArgumentCountError' : 'TypeError') ? 'Y' : 'N'; }",
+    expect => "Y"
+);
+php_test!(args_types_reflection,
+    code => "$f = new ReflectionFunction('rustyphp_func_arg_defaults'); foreach ($f->getParameters() as $p) { echo $p->getType(), $p->allowsNull() ? '?' : '', ','; } echo $f->getReturnType();",
+    expect => "int,int,string,bool?,string"
+);
+php_test!(args_defaults_reflection,
+    code => "$f = new ReflectionFunction('rustyphp_func_arg_defaults'); echo $f->getNumberOfParameters(), '|', $f->getNumberOfRequiredParameters(), '|', $f->getParameters()[3]->isOptional() ? 'Y' : 'N';",
+    expect => "4|1|Y"
+);
+
+/// Literal defaults of Option parameters are Some, null is still None
+#[php_func]
+fn rustyphp_func_arg_opt_default(#[default = "x"] p1: Option<String>, #[default = 3] p2: Option<i64>) -> String {
+    format!("{:?}|{:?}", p1, p2)
+}
+php_test!(args_opt_default,
+    code => "echo rustyphp_func_arg_opt_default(), ',', rustyphp_func_arg_opt_default(null, 4);",
+    expect => "Some(\"x\")|Some(3),None|Some(4)"
+);
+
+/// A trailing Variadic collects the remaining arguments
+#[php_func]
+fn rustyphp_func_arg_variadic(p1: String, nums: Variadic<i64>) -> String {
+    format!("{}={}", p1, nums.iter().sum::<i64>())
+}
+php_test!(args_variadic,
+    code => "echo rustyphp_func_arg_variadic('a'), ',', rustyphp_func_arg_variadic('b', 1, 2, 3), ',', rustyphp_func_arg_variadic('c', ...[4, 5]);",
+    expect => "a=0,b=6,c=9"
+);
+// the engine verifies the declared int type (and coerces '3' in weak mode)
+php_test!(args_variadic_count,
+    code => "try { rustyphp_func_arg_variadic(); } catch (TypeError $e) { echo $e->getMessage(); }",
+    expect => "rustyphp_func_arg_variadic() expects at least 1 argument, 0 given"
+);
+php_test!(args_variadic_err,
+    code => "echo rustyphp_func_arg_variadic('a', 1, '3'), ','; try { rustyphp_func_arg_variadic('a', 1, 'x'); } catch (TypeError $e) { echo get_class($e); }",
+    expect => "a=4,TypeError"
 );
+php_test!(args_variadic_reflection,
+    code => "$f = new ReflectionFunction('rustyphp_func_arg_variadic'); echo $f->getNumberOfParameters(), '|', $f->getNumberOfRequiredParameters(), '|', $f->isVariadic() ? 'Y' : 'N';",
+    expect => "2|1|Y"
+);
+
+/// The engine only checks that every row is an array, the elements are checked by the conversion
+#[php_func]
+fn rustyphp_func_arg_variadic_rows(rows: Variadic<Vec<i64>>) -> i64 {
+    rows.iter().map(|row| row.iter().sum::<i64>()).sum()
+}
+php_test!(args_variadic_conversion_err,
+    code => "echo rustyphp_func_arg_variadic_rows([1, 2], [3]), ','; try { rustyphp_func_arg_variadic_rows([1], [2, 'x']); } catch (TypeError $e) { echo $e->getMessage(); }",
+    expect => "6,rustyphp_func_arg_variadic_rows(): Argument #2 ($rows)[1] must be of type int, string given"
+);
+
+/// ByRef parameters write back to the caller's variable
+#[php_func]
+fn rustyphp_func_arg_by_ref(p1: String, mut len: ByRef<i64>, words: Option<ByRef<Vec<String>>>) {
+    let old: i64 = len.get().unwrap_or(0);
+    zend_try_option!(len.set(old + p1.len() as i64));
+    if let Some(mut words) = words {
+        zend_try_option!(words.set(p1.split(' ').map(|word| word.to_owned()).collect()));
+    }
+}
+php_test!(args_by_ref, leak_check => true,
+    code => "$n = 1; $w = 'old'; rustyphp_func_arg_by_ref('ab', $n); rustyphp_func_arg_by_ref('hello world', $n, $w); echo $n, '|', implode(',', $w);",
+    expect => "14|hello,world"
+);
+php_test!(args_by_ref_reflection,
+    code => "$f = new ReflectionFunction('rustyphp_func_arg_by_ref'); echo $f->getParameters()[0]->isPassedByReference() ? 'Y' : 'N', $f->getParameters()[1]->isPassedByReference() ? 'Y' : 'N';",
+    expect => "NY"
+);
+
+#[php_func]
+fn rustyphp_func_arg_matrix(p1: Vec<Vec<f64>>) {
+    let sums: Vec<String> = p1.iter().map(|row| format!("{}", row.iter().fold(0.0, |acc, x| acc + x))).collect();
+    println!("RUST_SUMS({})", sums.join(","))
+}
+php_test!(arr_nested, code => "rustyphp_func_arg_matrix(array(array(1.5, 2.5), array(), array(0.25)));", expect => "RUST_SUMS(4,0,0.25)");
+php_test!(arr_nested_err,
+    code => "try { rustyphp_func_arg_matrix(array(array(1.5), array(\"x\"))); } catch (TypeError $e) { echo $e->getMessage(); }",
+    expect => "rustyphp_func_arg_matrix(): Argument #1 ($p1)[1][0] must be of type float, string given"
+);
+
+/// Nested arrays are separated again before they are modified, never the caller's rows
+#[php_func]
+fn rustyphp_func_arg_rows_mut(p1: &mut ZendArray) {
+    for (_, row) in p1.iter_mut() {
+        let row: Result<&mut ZendArray, _> = From::from(row);
+        zend_try_option!(zend_try!(row).push(0));
+    }
+}
+php_test!(arr_nested_mut, leak_check => true,
+    code => "$a = array(array(1), array(2)); $b = $a; rustyphp_func_arg_rows_mut($a); echo count($a[0]), count($b[1]);",
+    expect => "11"
+);
+
+#[php_func]
+fn rustyphp_func_arg_opt_strs(p1: Vec<Option<String>>) {
+    println!("RUST_PRINTLN({:?})", p1)
+}
+php_test!(arr_opt, code => "rustyphp_func_arg_opt_strs(array(\"a\", null, \"b\"));", expect => "RUST_PRINTLN([Some(\"a\"), None, Some(\"b\")])");
+
+#[php_func]
+fn rustyphp_func_arg_tuple(p1: (i64, String)) {
+    println!("RUST_PRINTLN({}, {})", p1.0, p1.1)
+}
+php_test!(tuple, code => "rustyphp_func_arg_tuple(array(42, \"answer\"));", expect => "RUST_PRINTLN(42, answer)");
+php_test!(tuple_err,
+    code => "try { rustyphp_func_arg_tuple(array(42)); } catch (TypeError $e) { echo $e->getMessage(); }",
+    expect => "rustyphp_func_arg_tuple(): Argument #1 ($p1) must contain exactly 2 elements, 1 given"
+);
+
+#[php_func]
+fn rustyphp_func_arg_zstr(p1: ZendStr) -> ZendStr {
+    println!("RUST_PRINTLN({:?}, {}, {})", p1, p1.len(), p1.hash() != 0);
+    p1
+}
+php_test!(zstr, leak_check => true, code => "var_dump(rustyphp_func_arg_zstr(\"hello\"));", expect => "RUST_PRINTLN(\"hello\", 5, true)\nstring(5) \"hello\"");
@@ -0,0 +1,70 @@
+use rustyphp::{php_func, AssignTo, Callable, ConversionError, PhpException, Zval, ZvalGuard};
+
+/// Call `func` for every value, joining the results
+#[php_func]
+fn rustyphp_func_callable_map(values: Vec<i64>, func: Callable) -> Result<String, PhpException> {
+    let mut results = vec![];
+    for value in values {
+        let mut ret = func.call(&[&value])?;
+        let ret: Result<String, ConversionError> = From::from(&mut *ret);
+        results.push(ret?);
+    }
+    Ok(results.join(","))
+}
+php_test!(callable_closure,
+    code => "echo rustyphp_func_callable_map(array(1, 2), function ($x) { return $x * 2; });",
+    expect => "2,4"
+);
+php_test!(callable_name,
+    code => "function cb_hex($x) { return dechex($x); } echo rustyphp_func_callable_map(array(10, 255), 'cb_hex'), ' ', rustyphp_func_callable_map(array(-1), 'abs');",
+    expect => "a,ff 1"
+);
+php_test!(callable_method,
+    code => "class Cb { public $n = 10; function add($x) { return $x + $this->n; } static function neg($x) { return -$x; } }
+             echo rustyphp_func_callable_map(array(1), array(new Cb, 'add')), ' ', rustyphp_func_callable_map(array(1), 'Cb::neg');",
+    expect => "11 -1"
+);
+// __call and __callStatic trampolines are released by every call and resolved again
+// (or by the callable itself if it is never called)
+php_test!(callable_magic, leak_check => true,
+    code => "class Magic { function __call($name, $args) { return $name . $args[0]; } static function __callStatic($name, $args) { return strtoupper($name) . $args[0]; } }
+             echo rustyphp_func_callable_map(array(1, 2, 3), array(new Magic, 'foo')), ' ', rustyphp_func_callable_map(array(4, 5), 'Magic::bar'), '|', rustyphp_func_callable_map(array(), array(new Magic, 'foo'));",
+    expect => "foo1,foo2,foo3 BAR4,BAR5|"
+);
+php_test!(callable_invokable,
+    code => "class Inv { function __invoke($x) { return \"<$x>\"; } } echo rustyphp_func_callable_map(array(1, 2), new Inv);",
+    expect => "<1>,<2>"
+);
+php_test!(callable_exception,
+    code => "try { rustyphp_func_callable_map(array(1, 2), function ($x) { throw new LogicException(\"bad $x\"); }); } catch (LogicException $e) { echo $e->getMessage(); }",
+    expect => "bad 1"
+);
+php_test!(callable_invalid,
+    code => "try { rustyphp_func_callable_map(array(1), 'no_such_function'); } catch (TypeError $e) { echo get_class($e); }",
+    expect => "TypeError"
+);
+php_test!(callable_reflection,
+    code => "echo (new ReflectionFunction('rustyphp_func_callable_map'))->getParameters()[1]->isCallable() ? 'y' : 'n';",
+    expect => "y"
+);
+
+/// Call `func` with zvals created on the Rust side
+#[php_func]
+fn rustyphp_func_callable_call_with(func: Callable, s: String, n: i64) -> Result<String, PhpException> {
+    let mut args = vec![Zval::new(), Zval::new()];
+    let err = s.assign_to(&mut args[0]).or_else(|| n.assign_to(&mut args[1]));
+    let ret = match err {
+        Some(err) => Err(err.into()),
+        None => func.call_with(&mut args)
+    };
+    for arg in args {
+        drop(ZvalGuard(arg));
+    }
+    let mut ret = ret?;
+    let ret: Result<String, ConversionError> = From::from(&mut *ret);
+    Ok(ret?)
+}
+php_test!(callable_call_with,
+    code => "echo rustyphp_func_callable_call_with('str_repeat', 'ab', 3);",
+    expect => "ababab"
+);
@@ -0,0 +1,33 @@
+use rustyphp::{php_func, AssignTo, CallContext, Zval};
+
+/// func_get_args()-style access to the passed arguments
+#[php_func]
+fn rustyphp_func_ctx_args(p1: i64, ctx: &mut CallContext, p2: Option<i64>) {
+    let passed: Vec<String> = (0..ctx.arg_count() + 1).map(|idx| match ctx.get_arg::<i64>(idx) {
+        Some(Ok(value)) => value.to_string(),
+        Some(Err(_)) => "?".to_owned(),
+        None => "-".to_owned()
+    }).collect();
+    let msg = format!("{}|{:?}|{}", p1, p2, passed.join(","));
+    zend_try_option!(msg.assign_to(ctx.return_value()));
+}
+php_test!(ctx_args, code => "echo rustyphp_func_ctx_args(1), ' ', rustyphp_func_ctx_args(1, 2);", expect => "1|None|1,- 1|Some(2)|1,2,-");
+php_test!(ctx_args_reflection,
+    code => "echo (new ReflectionFunction('rustyphp_func_ctx_args'))->getNumberOfParameters();",
+    expect => "2"
+);
+
+/// Slots borrowed by parameters are not handed out by the context again
+#[php_func]
+fn rustyphp_func_ctx_borrowed(p1: &mut Zval, ctx: &mut CallContext, p2: i64) -> String {
+    let free = (ctx.arg(0).is_some(), ctx.arg(1).is_some());
+    format!("{:?}|{}|{}", free, p1.type_name(), p2)
+}
+php_test!(ctx_borrowed, code => "echo rustyphp_func_ctx_borrowed('a', 2);", expect => "(false, true)|string|2");
+
+#[php_func]
+fn rustyphp_func_ctx_caller(ctx: &mut CallContext) -> String {
+    let (file, line) = ctx.caller();
+    format!("{}:{}", file, line)
+}
+php_test!(ctx_caller, code => "\n\necho rustyphp_func_ctx_caller();", expect => "Command line code:3");
@@ -0,0 +1,41 @@
+use rustyphp::php_func;
+
+#[php_func(deprecated)]
+fn rustyphp_func_flag_deprecated() -> i64 {
+    1
+}
+php_test!(flag_deprecated,
+    code => "set_error_handler(function ($no, $str) { echo $no === E_DEPRECATED ? 'D:' : '?:', $str, '|'; }); echo rustyphp_func_flag_deprecated(), '|', (new ReflectionFunction('rustyphp_func_flag_deprecated'))->isDeprecated() ? 'Y' : 'N';",
+    expect => "D:Function rustyphp_func_flag_deprecated() is deprecated|1|Y"
+);
+
+#[php_func(deprecated = "use rustyphp_func_flag_deprecated() instead", alias = "rustyphp_func_flag_deprecated_msg_alias")]
+fn rustyphp_func_flag_deprecated_msg() -> i64 {
+    2
+}
+php_test!(flag_deprecated_msg,
+    code => "set_error_handler(function ($no, $str) { echo $no === E_DEPRECATED ? 'D:' : '?:', $str, '|'; }); echo rustyphp_func_flag_deprecated_msg();",
+    expect => "D:Function rustyphp_func_flag_deprecated_msg() is deprecated, use rustyphp_func_flag_deprecated() instead|2"
+);
+// the message names the alias which was called
+php_test!(flag_deprecated_msg_alias,
+    code => "set_error_handler(function ($no, $str) { echo $str, '|'; }); echo rustyphp_func_flag_deprecated_msg_alias();",
+    expect => "Function rustyphp_func_flag_deprecated_msg_alias() is deprecated, use rustyphp_func_flag_deprecated() instead|2"
+);
+
+#[php_func(return_ref)]
+fn rustyphp_func_flag_return_ref() {}
+php_test!(flag_return_ref,
+    code => "echo (new ReflectionFunction('rustyphp_func_flag_return_ref'))->returnsReference() ? 'Y' : 'N', (new ReflectionFunction('rustyphp_func_flag_deprecated'))->returnsReference() ? 'Y' : 'N';",
+    expect => "YN"
+);
+
+/// The function isn't run if the error handler throws for the deprecation
+#[php_func(deprecated = "it has side effects")]
+fn rustyphp_func_flag_deprecated_throws() {
+    println!("RUST_RUN");
+}
+php_test!(flag_deprecated_throws,
+    code => "set_error_handler(function ($no, $str) { throw new ErrorException($str); }); try { rustyphp_func_flag_deprecated_throws(); } catch (ErrorException $e) { echo 'E:', $e->getMessage(); }",
+    expect => "E:Function rustyphp_func_flag_deprecated_throws() is deprecated, it has side effects"
+);
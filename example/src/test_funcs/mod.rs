@@ -1,2 +1,7 @@
+pub mod alias;
 pub mod args;
+pub mod callable;
+pub mod context;
+pub mod flags;
+pub mod ns;
 pub mod ret;
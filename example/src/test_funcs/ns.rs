@@ -0,0 +1,14 @@
+use rustyphp::php_func;
+
+#[php_func(namespace = "RustyPhp\\Geo")]
+fn rustyphp_func_ns_distance(p1: f64, p2: f64) -> f64 {
+    (p1 - p2).abs()
+}
+php_test!(ns, code => "echo RustyPhp\\Geo\\rustyphp_func_ns_distance(1.5, 4), '|', function_exists('rustyphp_func_ns_distance') ? 'Y' : 'N';", expect => "2.5|N");
+php_test!(ns_case_insensitive, code => "echo \\rustyphp\\GEO\\RUSTYPHP_FUNC_NS_DISTANCE(1, 2);", expect => "1");
+php_test!(ns_count_error, status_success => false,
+    code => "RustyPhp\\Geo\\rustyphp_func_ns_distance();",
+    expect => "RustyPhp\\Geo\\rustyphp_func_ns_distance() expects exactly 2 arguments, 0 given",
+    // ArgumentCountError since PHP 7.1, TypeError before
+    check_func => |expect: &str, stdout: &str, _| assert!(stdout.trim().starts_with("Fatal error: Uncaught ") && stdout.contains(expect), "EXPECTED:\n{}\nGOT:\n{}\n", expect, stdout.trim())
+);
@@ -1,4 +1,6 @@
 use rustyphp::*;
+use std::fmt::Write;
+use rustyphp::types::zstr::{ZendStr, ZendStringBuilder};
 
 #[php_func]
 fn rustyphp_func_ret_u32() -> u32 {
@@ -6,6 +8,19 @@ fn rustyphp_func_ret_u32() -> u32 {
 }
 php_test!(i32, code => "var_dump(rustyphp_func_ret_u32());", expect => "int(42)");
 
+/// The error of a fallible function is thrown, its `Ok` value is returned
+#[php_func]
+fn rustyphp_func_ret_result(ok: bool) -> Result<i64, String> {
+    match ok {
+        true => Ok(42),
+        false => Err("not ok".to_owned())
+    }
+}
+php_test!(result,
+    code => "echo rustyphp_func_ret_result(true), ','; try { rustyphp_func_ret_result(false); } catch (Exception $e) { echo get_class($e), '|', $e->getMessage(); }",
+    expect => "42,Exception|not ok"
+);
+
 /// This causes a copy of the string literal into a zend string structure
 #[php_func]
 fn rustyphp_func_ret_str() -> &'static str {
@@ -15,6 +30,7 @@ php_test!(str, code => "var_dump(rustyphp_func_ret_str());", expect => "string(4
 
 /// This causes a copy of the string into a zend string structure
 /// That means the string is allocated on stack and then copied (rust stack and zend MM)
+/// (see `rustyphp_func_ret_builder` for how to avoid that)
 #[php_func]
 fn rustyphp_func_ret_string() -> String {
     format!("hello {}", "world")
@@ -37,3 +53,38 @@ fn rustyphp_func_ret_arr_str() -> Vec<String> {
     g
 }
 php_test!(arr_str, code => "echo implode('', rustyphp_func_ret_arr_str());", expect => "aaaaaaaaaa");
+
+#[php_func]
+fn rustyphp_func_ret_arr_nested() -> Vec<Vec<f64>> {
+    vec![vec![1.5, 2.0], vec![], vec![0.25]]
+}
+php_test!(arr_nested, code => "echo json_encode(rustyphp_func_ret_arr_nested());", expect => "[[1.5,2],[],[0.25]]");
+
+#[php_func]
+fn rustyphp_func_ret_tuple() -> (i64, Option<String>) {
+    (42, None)
+}
+php_test!(tuple, code => "var_export(rustyphp_func_ret_tuple());", expect => "array (\n  0 => 42,\n  1 => NULL,\n)");
+
+/// Interned strings are shared and must never be freed by the zval destructor
+#[php_func]
+fn rustyphp_func_ret_interned() -> ZendStr {
+    ZendStr::from_str("interned").intern()
+}
+php_test!(interned, leak_check => true, code => "$a = rustyphp_func_ret_interned(); $b = rustyphp_func_ret_interned(); unset($a); var_dump($b);", expect => "string(8) \"interned\"");
+
+/// The result is written directly into the zend string structure (no copy)
+#[php_func]
+fn rustyphp_func_ret_builder(n: i64) -> ZendStr {
+    let mut builder = ZendStringBuilder::with_capacity(8);
+    builder.push_str("[");
+    for i in 0..n {
+        if i > 0 {
+            builder.push_str(",");
+        }
+        write!(builder, "{}", i).unwrap();
+    }
+    builder.push_str("]");
+    builder.finish()
+}
+php_test!(builder, leak_check => true, code => "var_dump(count(json_decode(rustyphp_func_ret_builder(100000))));", expect => "int(100000)");
@@ -4,6 +4,8 @@
 //TODO: write_property/read_property handlers
 //TODO: zval instance caching (cache a constructed instance the first time rust-->zend boundaries are crossed/ when dynamic properties are used)
 
+use rustyphp::{php_cls, php_methods};
+
 /// The difference between `rust_property` and `property` is that property is accessible from PHP
 #[php_cls]
 struct RustyPhpBasicObj {
@@ -12,3 +14,24 @@ struct RustyPhpBasicObj {
 }
 
 php_test!(register, code => "var_dump(in_array('RustyPhpBasicObj', get_declared_classes()));", expect => "bool(true)");
+php_test!(instantiate,
+    code => "$obj = new RustyPhpBasicObj; echo get_class($obj), '|', (new ReflectionClass('RustyPhpBasicObj'))->isInternal() ? 'Y' : 'N';",
+    expect => "RustyPhpBasicObj|Y"
+);
+
+/// Associated functions are exported as PHP functions (methods on the class entry are still TODO)
+#[php_methods]
+impl RustyPhpBasicObj {
+    fn rustyphp_basic_obj_sum(a: i64, b: i64) -> i64 {
+        a + b
+    }
+
+    #[php_func(name = "rustyphp_basic_obj_greet", alias = "rustyphp_basic_obj_hello")]
+    fn greet(name: String) -> String {
+        format!("hello {}", name)
+    }
+}
+php_test!(methods,
+    code => "echo rustyphp_basic_obj_sum(1, 2), '|', rustyphp_basic_obj_greet('a'), '|', rustyphp_basic_obj_hello('b');",
+    expect => "3|hello a|hello b"
+);
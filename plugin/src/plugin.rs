@@ -1,526 +1,769 @@
-#![feature(plugin_registrar, rustc_private)]
-
-extern crate aster;
-extern crate syntax;
-extern crate rustc_plugin;
-extern crate rustyphp;
-
-use std::cell::RefCell;
-
-use aster::AstBuilder;
-use aster::ident::ToIdent;
-use rustc_plugin::Registry;
-use syntax::ast;
-use syntax::ast::{Arg, ExprKind, Expr, FunctionRetTy, Ident, Item, ItemKind, ImplItem, ImplItemKind, MetaItem, MutTy, Mutability, VariantData, PatKind, Stmt, Ty, TyKind, TokenTree};
-use syntax::ext::base::{MacResult, MacEager, ExtCtxt};
-use syntax::codemap::{DUMMY_SP, Span};
-use syntax::ext::base::Annotatable;
-use syntax::ext::base::SyntaxExtension::{MultiModifier, MultiDecorator};
-use syntax::parse::token::Token;
-use syntax::parse::token::intern;
-use syntax::ptr::P;
-use syntax::util::small_vector::SmallVector;
-
-thread_local!(static ALREADY_COMPILED: RefCell<bool> = RefCell::new(false));
-thread_local!(static REGISTERED_FUNCS: RefCell<Vec<RegisteredFunc>> = RefCell::new(vec![]));
-thread_local!(static REGISTERED_CLS: RefCell<Vec<RegisteredClass>> = RefCell::new(vec![]));
-
-//TODO: Replace panic! with span errors
-
-#[derive(Debug, Clone)]
-struct RegisteredFunc {
-    /// The internal name (mostly zif_ prefix for the C-definition)
-    internal_name: String,
-    /// The path to the func (mod-wise)
-    mod_path: Vec<Ident>,
-    /// The real name of the func
-    real_name: String,
-    /// The parameters a function can take
-    args: Option<Vec<Arg>>,
-    required_args: usize
-}
-
-#[derive(Debug, Clone)]
-struct RegisteredClass {
-    /// The real name of the class
-    name: String,
-    /// The path to the func (mod-wise)
-    mod_path: Vec<Ident>,
-}
-
-#[plugin_registrar]
-pub fn registrar(reg: &mut Registry) {
-    reg.register_syntax_extension(intern("php_func"), MultiDecorator(Box::new(expand_php_func)));
-    reg.register_syntax_extension(intern("php_cls"), MultiModifier(Box::new(modify_php_cls)));
-    reg.register_syntax_extension(intern("php_methods"), MultiModifier(Box::new(modify_php_methods)));
-    reg.register_macro("get_php_funcs", get_php_funcs);
-    reg.register_macro("get_php_classes", get_php_classes);
-}
-
-/// Assign the value of "ret" to the return_value zval "zv"
-fn build_assign_ret(builder: &AstBuilder, field: Option<&P<Ty>>, src: P<Expr>, target: P<Expr>) -> Vec<Stmt> {
-    match field {
-        None => vec![],
-        Some(_) => {
-            let mac_item = builder.item().mac().path().id("zend_try_option").build()
-                .expr().method_call("assign_to").build(src).with_arg(target)
-                .build()
-                .build();
-
-            vec![
-                builder.stmt()
-                    .semi().build(mk_macro_expr(&builder, mac_item))
-            ]
-        }
+//! Attributes declaring the PHP interface of an extension
+//!
+//! Every annotated item registers itself (see `rustyphp::registry`), so functions and classes
+//! can be declared anywhere in the crate and in any order relative to `php_ext!`.
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+#[macro_use]
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, Span, TokenStream as TokenStream2};
+use syn::parse::{Parse, ParseStream};
+use syn::spanned::Spanned;
+use syn::{AttributeArgs, Expr, ExprLit, Fields, FnArg, GenericArgument, Ident, ImplItem, Item, ItemFn, ItemImpl, ItemStruct, Lit, LitStr, Meta, NestedMeta, Pat, PathArguments, ReturnType, Signature, Type};
+
+/// #[php_func] to declare exported php functions
+///
+/// Trailing `Option<T>` parameters are optional (None if omitted), any parameter can be given
+/// a default value using `#[default = 10]`. A last `Variadic<T>` parameter collects all
+/// remaining arguments (`...$name`), `ByRef<T>` parameters are passed by reference (`&$name`).
+/// Defaults are not visible to reflection: the PHP 7.0 `zend_internal_arg_info` has no default
+/// value field (it was added in PHP 8.0) and `ReflectionParameter` only reads defaults of user
+/// functions, so these parameters are reported as optional without a value.
+/// A `&mut CallContext` parameter (not a PHP argument) gives access to the call itself.
+///
+/// The PHP type declarations are derived from the Rust types (`i64` is `int`, `Option<String>` is
+/// `?string`, ...), `#[php_type = "?Acme\\Point"]` declares them explicitly (`"mixed"` for none).
+/// Pseudo types the PHP 7.0 arginfo can't express (`iterable`, `object`, `void`, unions, ...) are rejected.
+/// Functions returning `Result<T, E>` throw the error (anything a `PhpException` can be created from).
+///
+/// `#[php_func(namespace = "Acme\\Geo")]` registers the function in a namespace (`Acme\Geo\distance`),
+/// `name = "str_distance"` exports it under another name than the Rust one. `alias = "strdist"` and
+/// `deprecated_alias = "old_name"` (E_DEPRECATED on call) register additional names, both can be repeated.
+///
+/// Flags: `deprecated` (or `deprecated = "use foo() instead"` to name a replacement, this message is
+/// raised by the wrapper instead of the engine and names the alias the function was called by),
+/// `return_ref` (the function stores a reference in the return value, see `CallContext`).
+/// PHP 8.2's `compile_time_eval` is rejected: its flag value means something else in the PHP 7.0
+/// layout and there is no PHP 8 support yet.
+#[proc_macro_attribute]
+pub fn php_func(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let func = parse_macro_input!(item as ItemFn);
+    expand(parse_func_meta(&args).and_then(|opts| expand_php_func(func, opts)))
+}
+
+/// #[php_cls] to declare a struct as PHP class (registered during MINIT)
+#[proc_macro_attribute]
+pub fn php_cls(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let cls = parse_macro_input!(item as ItemStruct);
+    expand(Ok(expand_php_cls(cls)))
+}
+
+/// #[php_methods] on an impl block exports its associated functions like `#[php_func]` on each of them
+///
+/// Options of a single function go into a `#[php_func(...)]` on it. The functions are global PHP
+/// functions: methods can't be registered with the class entry yet (`#[php_cls]` objects carry no
+/// Rust state, so there is nothing to bind `self` to) and methods taking `self` are rejected.
+#[proc_macro_attribute]
+pub fn php_methods(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let imp = parse_macro_input!(item as ItemImpl);
+    expand(expand_php_methods(imp))
+}
+
+/// #[php_exception(name = "MyExt\\MyException", extends = "RuntimeException")] on an error enum
+/// declares a PHP exception class per enum (and optionally per variant using the same attribute)
+/// and implements `From<Enum> for PhpException`, so the errors can be thrown using `zend_try!`
+#[proc_macro_attribute]
+pub fn php_exception(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let item = parse_macro_input!(item as Item);
+    expand(expand_php_exception(args, item))
+}
+
+fn expand(res: syn::Result<TokenStream2>) -> TokenStream {
+    match res {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into()
     }
 }
 
-fn modify_php_cls(ectx: &mut ExtCtxt, span: Span, meta_item: &MetaItem, orig_item: Annotatable) -> Annotatable {
-    let mod_item;
-    match orig_item {
-       Annotatable::Item(ref item) => {
-            let mut item = (**item).clone();
-            match item.node {
-                ItemKind::Struct(ref mut var_data, _) => {
-                    match *var_data {
-                        VariantData::Struct(ref mut fields, _) => {
-                            // Append some custom fields
-                            let builder = AstBuilder::new();
-                            /*let field = builder.struct_field("test").ty().i32();
-                            fields.push(field);*/
-                        },
-                        _ => panic!("php_cls: expected struct, got {:?}", var_data)
-                    }
-                },
-                _ => panic!("php_cls: expected Struct got {:?}", item.node)
-            }
-            mod_item = item;
-        },
-        _ => panic!("php_cls: expected Item, got {:?}", orig_item)
+fn expand_php_func(mut func: ItemFn, opts: FuncOptions) -> syn::Result<TokenStream2> {
+    let name = func.sig.ident.clone();
+    let wrapper = wrapper_ident(&name);
+    let exports = expand_exports(&mut func.sig, quote!(#name), &wrapper, opts)?;
+    Ok(quote! {
+        #func
+
+        #exports
+    })
+}
+
+/// The wrapper of the function called by `call` and its `PhpFunction` registrations
+fn expand_exports(sig: &mut Signature, call: TokenStream2, wrapper: &Ident, opts: FuncOptions) -> syn::Result<TokenStream2> {
+    let name = sig.ident.clone();
+    let qualify = |name: &str| match opts.namespace {
+        Some(ref namespace) => format!("{}\\{}", namespace, name),
+        None => name.to_owned()
+    };
+    let php_name = qualify(&opts.name.clone().unwrap_or_else(|| name.to_string()));
+    let (args, context) = fn_args(sig)?;
+    let wrapper_fn = mk_wrapper(wrapper, call, &php_name, &args, context, &sig.output, opts.deprecated_message.as_ref().map(|msg| &msg[..]));
+    let required_args = required_args(&args) as u32;
+    let return_type = opt_str(return_type(sig));
+    let arg_infos = args.iter().map(|arg| {
+        let name = &arg.name;
+        let variadic = arg.variadic.is_some();
+        let by_ref = arg.by_ref;
+        let type_hint = opt_str(arg.type_hint.clone());
+        quote!(::rustyphp::PhpArg { name: concat!(#name, "\0"), variadic: #variadic, by_ref: #by_ref, type_hint: #type_hint })
+    }).collect::<Vec<_>>();
+    // aliases share the handler (and so the name used in error messages)
+    let mut entries = vec![(php_name.clone(), mk_flags(&opts.flags))];
+    for &(ref alias, deprecated) in &opts.aliases {
+        // function names are case insensitive, the engine would refuse to load the extension
+        if entries.iter().any(|(name, _)| name.eq_ignore_ascii_case(&qualify(alias))) {
+            return Err(syn::Error::new(sig.ident.span(), format!("php_func: duplicate name `{}`", alias)));
+        }
+        let mut flags = opts.flags.clone();
+        // a deprecation message is raised by the wrapper (for all names) already
+        if deprecated && !flags.contains(&"ZEND_ACC_DEPRECATED") && opts.deprecated_message.is_none() {
+            flags.push("ZEND_ACC_DEPRECATED");
+        }
+        entries.push((qualify(alias), mk_flags(&flags)));
     }
-    //Register the class
-    REGISTERED_CLS.with(|rf| {
-        (*rf.borrow_mut()).push(RegisteredClass {
-            name: format!("{}", mod_item.ident.name.as_str()),
-            mod_path: ectx.mod_path()
-        });
+    let submits = entries.iter().map(|(php_name, flags)| quote! {
+        ::rustyphp::inventory::submit! {
+            ::rustyphp::PhpFunction {
+                name: concat!(#php_name, "\0"),
+                handler: #wrapper,
+                args: &[#(#arg_infos),*],
+                required_args: #required_args,
+                return_type: #return_type,
+                flags: #flags
+            }
+        }
     });
-    Annotatable::Item(P(mod_item))
-}
-
-fn modify_php_methods(ectx: &mut ExtCtxt, span: Span, meta_item: &MetaItem, orig_item: Annotatable) -> Annotatable {
-    let mut mod_item;
-    //Assign php_func to all methods
-    match orig_item {
-        Annotatable::Item(ref item) => {
-            let mut item = (**item).clone();
-            match item.node {
-                ItemKind::Impl(_, _, _, _, _, ref mut impl_items) => {
-                    //println!("{:?}", impl_items);
-                    // Iterate all over members and find the functions which we need to decorate
-                    let builder = AstBuilder::new();
-                    for impl_item in impl_items.iter_mut() {
-                        match impl_item.node {
-                            ImplItemKind::Method(_, _) => {
-                                impl_item.attrs.push(builder.attr().word("php_func"));
-                            },
-                            // skip
-                            _ => {}
-                        }
+    Ok(quote! {
+        #wrapper_fn
+
+        #(#submits)*
+    })
+}
+
+fn expand_php_cls(cls: ItemStruct) -> TokenStream2 {
+    let name = cls.ident.to_string();
+    quote! {
+        #cls
+
+        ::rustyphp::inventory::submit! {
+            ::rustyphp::PhpClass {
+                name: #name,
+                extends: None,
+                register: {
+                    unsafe fn register() -> ::std::result::Result<(), ::std::string::String> {
+                        ::rustyphp::define_class(#name, ::std::ptr::null_mut());
+                        Ok(())
                     }
-                },
-                _ => panic!("php_methods: expected impl, got {:?}", item.node)
+                    register
+                }
             }
-            mod_item = item;
-        },
-        _ => panic!("php_methods: expected Item got {:?}")
-    }
-    // remove all attributes from the trait (hopefully shouldnt be used or we need to remove php_methods manually)
-    //println!("{:?}", mod_item.attrs);
-    //mod_item.attrs.clear();
-    Annotatable::Item(P(mod_item))
-}
-
-/// #[php_func] to declare exported php functions/methods
-fn expand_php_func(ectx: &mut ExtCtxt, span: Span, _: &MetaItem, anno: &Annotatable, push: &mut FnMut(Annotatable)) {
-    ALREADY_COMPILED.with(|rf| {
-        let already_compiled = rf.borrow();
-        if *already_compiled {
-            ectx.span_err(span, "`php_func` cannot be used outside an extension. Make sure the `php_ext` macro is resolved BEFORE.");
         }
-    });
-    let builder = AstBuilder::new();
-
-    // Gather information about the original function
-    // like return_value type and params
-    let old_fn;
-    let fn_decl;
-    let method_sig;
-
-    match anno {
-        &Annotatable::Item(ref item) => {
-            method_sig = None;
-            old_fn = item.ident.name;
-            match item.node {
-                ItemKind::Fn(ref i_fndecl, _, _, _, _, _) => {
-                    fn_decl = i_fndecl;
-                },
-                _ => panic!("php_func: expected ItemFn, got {:?}", item.node)
+    }
+}
+
+fn expand_php_methods(mut imp: ItemImpl) -> syn::Result<TokenStream2> {
+    if !imp.generics.params.is_empty() {
+        return Err(syn::Error::new(imp.generics.span(), "php_methods: generic impls are not supported"));
+    }
+    if let Some((_, ref path, _)) = imp.trait_ {
+        return Err(syn::Error::new(path.span(), "php_methods: trait impls are not supported"));
+    }
+    // wrappers live next to the impl, the type is part of their name to keep them apart
+    let type_name = match *imp.self_ty {
+        Type::Path(ref path) => path.path.segments.last().map(|seg| seg.ident.clone()),
+        _ => None
+    };
+    let type_name = match type_name {
+        Some(name) => name,
+        None => return Err(syn::Error::new(imp.self_ty.span(), "php_methods: expected a struct or enum type"))
+    };
+    let self_ty = imp.self_ty.clone();
+    let mut exports = vec![];
+    for item in imp.items.iter_mut() {
+        let method = match *item {
+            ImplItem::Method(ref mut method) => method,
+            _ => continue
+        };
+        let mut opts = None;
+        let mut attrs = vec![];
+        for attr in method.attrs.drain(..) {
+            if !attr.path.is_ident("php_func") {
+                attrs.push(attr);
+                continue;
             }
-        },
-        // Method within impl
-        &Annotatable::ImplItem(ref item) => {
-            old_fn = item.ident.name;
-            match item.node {
-                ImplItemKind::Method(ref meth_sig, _) => {
-                    method_sig = Some(meth_sig);
-                    fn_decl = &meth_sig.decl;
-                },
-                _ => panic!("php_func: expected Method, got {:?}", item.node)
+            if opts.is_some() {
+                return Err(syn::Error::new(attr.span(), "php_methods: duplicate #[php_func] attribute"));
             }
+            let args: Vec<NestedMeta> = match attr.parse_meta()? {
+                Meta::Path(_) => vec![],
+                Meta::List(list) => list.nested.into_iter().collect(),
+                meta @ Meta::NameValue(_) => return Err(syn::Error::new(meta.span(), "php_methods: expected #[php_func(...)]"))
+            };
+            opts = Some(parse_func_meta(&args)?);
         }
-        _ => panic!("php_func: Expected Item, got {:?}", anno)
+        method.attrs = attrs;
+        let opts = match opts {
+            Some(opts) => opts,
+            None => parse_func_meta(&[])?
+        };
+        let name = method.sig.ident.clone();
+        let wrapper = format_ident!("zif_{}_{}", type_name, name);
+        exports.push(expand_exports(&mut method.sig, quote!(<#self_ty>::#name), &wrapper, opts)?);
     }
+    Ok(quote! {
+        #imp
 
-    // Function arguments
-    let mut fn_args = Vec::with_capacity(fn_decl.inputs.len());
-    for arg in &fn_decl.inputs {
-        fn_args.push(arg.clone());
-    }
-    let fn_arguments = Some(fn_args);
+        #(#exports)*
+    })
+}
 
-    // Return Value
-    let return_type = match fn_decl.output {
-        FunctionRetTy::None(_) | FunctionRetTy::Default(_) => None,
-        FunctionRetTy::Ty(ref _type) => Some(_type)
-    };
+/// The internal name of the wrapper (zif_ prefix like the C definitions)
+fn wrapper_ident(name: &Ident) -> Ident {
+    format_ident!("zif_{}", name)
+}
 
-    // Generate wrapper function, which is callable from PHP userland
-    let new_fn = format!("zif_{}", old_fn);
+/// A parameter of an exported function
+struct FuncArg {
+    name: String,
+    ty: Type,
+    span: Span,
+    /// Passed if the caller omits the argument
+    default: Option<TokenStream2>,
+    /// The element type of a `Variadic<T>` parameter
+    variadic: Option<Type>,
+    /// A `ByRef<T>` parameter
+    by_ref: bool,
+    /// The PHP type declaration (None for mixed)
+    type_hint: Option<String>
+}
 
-    // Call the old function (wrapper call)
-    let mut fn_expr_args = vec![];
-    let mut required_args = 0;
-    let mut block_builder = builder.block();
+/// `#[default = <expr>]` and `#[php_type = "<type>"]` on a parameter
+struct AttrValue<T>(T);
 
-    match fn_arguments {
-        None => {},
-        Some(ref args) => {
-            for arg in args {
-                required_args += 1;
-            }
-            let dummy_span = builder.expr().id(",").span;
-            if required_args > 0 {
-                // TODO: verify arg_count >= required_args
-                block_builder = block_builder.stmt().expr().build(mk_macro_expr(&builder,
-                    builder.item().mac().path().id("verify_arg_count").build()
-                    .with_arg(builder.expr().lit().str(old_fn))
-                    .with_arg(TokenTree::Token(dummy_span, Token::Comma))
-                    .with_arg(builder.id("_ex"))
-                    .with_arg(TokenTree::Token(dummy_span, Token::Comma))
-                    .with_arg(builder.expr().usize(required_args))
-                    .build()
-                ));
+impl<T: Parse> Parse for AttrValue<T> {
+    fn parse(input: ParseStream) -> syn::Result<AttrValue<T>> {
+        input.parse::<Token![=]>()?;
+        Ok(AttrValue(input.parse()?))
+    }
+}
+
+/// The PHP parameters of the function (for the arginfo and argument conversion) and the position of
+/// the `&mut CallContext` parameter if there is one,
+/// `#[default]` and `#[php_type]` attributes are consumed here (they'd be unknown attributes else)
+fn fn_args(sig: &mut Signature) -> syn::Result<(Vec<FuncArg>, Option<usize>)> {
+    let mut args = vec![];
+    let mut context = None;
+    for (pos, input) in sig.inputs.iter_mut().enumerate() {
+        let pat_ty = match *input {
+            FnArg::Typed(ref mut pat_ty) => pat_ty,
+            FnArg::Receiver(ref recv) => return Err(syn::Error::new(recv.span(), "php_func: methods taking self are not supported yet"))
+        };
+        if is_context(&pat_ty.ty) {
+            if context.is_some() {
+                return Err(syn::Error::new(pat_ty.span(), "php_func: only one CallContext parameter is allowed"));
             }
-            for (i, _) in args.iter().enumerate() {
-                let mac_item = builder.item().mac().path().id("zend_try").build()
-                    .expr().call()
-                    .path().ids(&["From", "from"]).build()
-                .with_arg(
-                    builder
-                        .expr().method_call("arg")
-                        .id("_ex")
-                        .with_arg(builder.expr().usize(i))
-                        .build()
-                )
-                .build()
-                .build();
-                fn_expr_args.push(mk_macro_expr(&builder, mac_item));
+            context = Some(pos);
+            continue;
+        }
+        let name = match *pat_ty.pat {
+            Pat::Ident(ref pat) => pat.ident.to_string(),
+            ref pat => return Err(syn::Error::new(pat.span(), "php_func: arguments have to be plain identifiers"))
+        };
+        let mut default = None;
+        let mut type_hint = php_type(&pat_ty.ty);
+        let mut attrs = vec![];
+        for attr in pat_ty.attrs.drain(..) {
+            if attr.path.is_ident("default") {
+                let AttrValue::<Expr>(expr) = syn::parse2(attr.tokens.clone())?;
+                default = Some(mk_default(&expr, &pat_ty.ty));
+            } else if attr.path.is_ident("php_type") {
+                let AttrValue::<LitStr>(decl) = syn::parse2(attr.tokens.clone())?;
+                if !supported_type(&decl.value()) {
+                    return Err(syn::Error::new(decl.span(), format!("php_func: type declaration `{}` is not supported by the PHP 7.0 arginfo", decl.value())));
+                }
+                type_hint = match decl.value() {
+                    ref decl if decl == "mixed" => None,
+                    decl => Some(decl)
+                };
+            } else {
+                attrs.push(attr);
             }
         }
+        pat_ty.attrs = attrs;
+        let variadic = type_param(&pat_ty.ty, "Variadic");
+        if variadic.is_some() && default.is_some() {
+            return Err(syn::Error::new(pat_ty.span(), format!("php_func: variadic parameter `{}` cannot have a default", name)));
+        }
+        // optional by reference: Option<ByRef<T>>
+        let by_ref = is_type(&pat_ty.ty, "ByRef") || type_param(&pat_ty.ty, "Option").map(|ty| is_type(&ty, "ByRef")).unwrap_or(false);
+        args.push((FuncArg { name, ty: (*pat_ty.ty).clone(), span: pat_ty.span(), default, variadic, by_ref, type_hint }, is_type(&pat_ty.ty, "Option")));
     }
-    // Variables prefixed with _ since we do (and sometimes cannot) check if they actually are used
-    // and else we get plenty ugly warnings
-
-    //For methods we have to detect the dispatch method: (take a look at MethodSig::ExplicitSelf_?)
-    //- (1) static dispatch (when the func we wrap, doesn't take a reference to self)
-    //- (2) dynamic-borrow / (3) dynamic-mut-borrow
-    match method_sig {
-        None => {
-            block_builder = block_builder
-                .stmt().let_id("_ret").call()
-                    .path().id(old_fn).build()
-                    .with_args(fn_expr_args)
-                    .build();
-        },
-        Some(method_sig) => {
-            match method_sig.explicit_self.node {
-                // (1)
-                ast::SelfKind::Static => {
-                    block_builder = block_builder
-                        .stmt().let_id("_ret").call()
-                        .path().id("Self").id(old_fn).build()
-                        .with_args(fn_expr_args)
-                        .build();
-                },
-                _ => panic!("explicitSelf type {:?} not supported yet", method_sig.explicit_self)
-            }
+    if let Some((arg, _)) = args.iter().rev().skip(1).find(|(arg, _)| arg.variadic.is_some()) {
+        return Err(syn::Error::new(arg.span, format!("php_func: variadic parameter `{}` has to be the last parameter", arg.name)));
+    }
+    // trailing Options are optional (null if omitted), the variadic parameter is skipped
+    for &mut (ref mut arg, is_option) in args.iter_mut().rev() {
+        if arg.variadic.is_some() || arg.default.is_some() {
+            continue;
+        }
+        if !is_option {
+            break;
         }
+        arg.default = Some(quote!(None));
     }
+    let args: Vec<FuncArg> = args.into_iter().map(|(arg, _)| arg).collect();
+    // the engine only knows the count of required arguments, they have to come first
+    let required = required_args(&args);
+    if let Some(arg) = args[required..].iter().find(|arg| arg.default.is_none() && arg.variadic.is_none()) {
+        return Err(syn::Error::new(arg.span, format!("php_func: required parameter `{}` follows an optional parameter", arg.name)));
+    }
+    Ok((args, context))
+}
 
-    let src = builder.expr().path().id("_ret").build();
-    let target = builder.expr().path().id("_zv").build();
-
-    // Assign the return value of the function to the return_value zval
-    let block = block_builder
-        .with_stmts(build_assign_ret(&builder, return_type, src, target)).build();
-
-    // Generate the function definition of the wrapper func
-    let fn_ = builder.item()
-        .pub_()
-        .attr().inline()
-        .fn_(new_fn.clone())
-        .arg_id("_ex").ty().ref_().mut_().ty().path()
-            .global().ids(&["rustyphp", "types", "execute_data", "ExecuteData"]).build() //execute_data as execute_data *
-        .arg_id("_zv").ty().ref_().mut_().ty().path()
-            .global().ids(&["rustyphp", "Zval"]).build() //return_value as zval *
-        .default_return()
-            .abi(syntax::abi::Abi::C)
-            .build(block.clone());
-
-    match method_sig {
-        None => {
-            // Register the function
-            REGISTERED_FUNCS.with(|rf| {
-                (*rf.borrow_mut()).push(RegisteredFunc {
-                    internal_name: new_fn,
-                    mod_path: ectx.mod_path(),
-                    real_name: format!("{}", old_fn),
-                    args: fn_arguments,
-                    required_args: required_args
-                });
-            });
-            push(Annotatable::Item(fn_))
-        },
-        Some(method_sig) => {
-            //Extract the fn_declaration...
-            let fn_decl = match fn_.node {
-                ItemKind::Fn(ref decl, _, _, _, _, _) => { (*decl).clone() },
-                _ => panic!("fn_decl: itemfn expected")
-            };
-            let method = builder
-                .method_sig()
-                .abi(syntax::abi::Abi::C)
-                .build_fn_decl(fn_decl);
-            //TODO ->aster
-            let iitem = ImplItem {
-                id: ast::DUMMY_NODE_ID,
-                ident: builder.id(new_fn),
-                vis: ast::Visibility::Public,
-                attrs: fn_.attrs.clone(),
-                node: ImplItemKind::Method(method, block),
-                span: DUMMY_SP,
-            };
+/// `()`
+fn is_unit(ty: &Type) -> bool {
+    match *ty {
+        Type::Tuple(ref tuple) => tuple.elems.is_empty(),
+        _ => false
+    }
+}
 
-            push(Annotatable::ImplItem(P(iitem)))
-        }
+/// `&mut CallContext`
+fn is_context(ty: &Type) -> bool {
+    match *ty {
+        Type::Reference(ref reference) => reference.mutability.is_some() && is_type(&reference.elem, "CallContext"),
+        _ => false
+    }
+}
+
+/// The number of leading arguments without a default (the variadic one is never required)
+fn required_args(args: &[FuncArg]) -> usize {
+    args.iter().take_while(|arg| arg.default.is_none() && arg.variadic.is_none()).count()
+}
+
+/// The type is `name<..>` (the last path segment is compared, so `std::option::Option` matches "Option")
+fn is_type(ty: &Type, name: &str) -> bool {
+    match *ty {
+        Type::Path(ref path) => path.path.segments.last().map(|seg| seg.ident == name).unwrap_or(false),
+        _ => false
     }
-    // println!("{}", syntax::print::pprust::item_to_string(&*fn_));
 }
 
-//TODO: integrate into aster
-fn mk_macro_expr(builder: &AstBuilder, mac_item: P<Item>) -> P<Expr> {
-    let mac = match mac_item.node {
-        ItemKind::Mac(ref mac) => mac.clone(),
-        _ => panic!("mac building failed"), //This cannot happen
+/// `T` of `name<T>`, e.g. of `Variadic<T>`
+fn type_param(ty: &Type, name: &str) -> Option<Type> {
+    let seg = match *ty {
+        Type::Path(ref path) => path.path.segments.last()?,
+        _ => return None
     };
-    // println!("{:?}", mac);
-    builder.expr().build_expr_kind(ExprKind::Mac(mac))
+    if seg.ident != name {
+        return None
+    }
+    match seg.arguments {
+        // skips lifetimes (ByRef<'a, T>)
+        PathArguments::AngleBracketed(ref args) => args.args.iter().filter_map(|arg| match *arg {
+            GenericArgument::Type(ref ty) => Some(ty.clone()),
+            _ => None
+        }).next(),
+        _ => None
+    }
 }
 
-/// Generate a std::ptr::null_mut() expression
-fn mk_null_ptr(builder: &AstBuilder) -> P<Expr> {
-    builder.expr().call()
-        .path().global().ids(&["std", "ptr", "null_mut"]).build()
-    .build()
+/// The PHP type declaration of a Rust type (None if there is none, e.g. for `&mut Zval`)
+///
+/// By-reference parameters are not declared, their value is only an output in most cases.
+/// Objects have no declaration before PHP 7.2, classes can be declared using `#[php_type]`.
+fn php_type(ty: &Type) -> Option<String> {
+    let seg = match *ty {
+        Type::Reference(ref reference) => return php_type(&reference.elem),
+        Type::Tuple(ref tuple) if !tuple.elems.is_empty() => return Some("array".to_owned()),
+        Type::Path(ref path) => path.path.segments.last()?,
+        _ => return None
+    };
+    let decl = match &*seg.ident.to_string() {
+        "i8" | "i16" | "i32" | "i64" | "isize" | "u8" | "u16" | "u32" | "u64" | "usize" => "int",
+        "f32" | "f64" => "float",
+        "bool" => "bool",
+        "str" | "String" | "ZendStr" => "string",
+        "Vec" | "HashMap" | "ZendArray" => "array",
+        "Callable" => "callable",
+        "Variadic" => return php_type(&type_param(ty, "Variadic")?),
+        "Option" => return php_type(&type_param(ty, "Option")?).map(|decl| match decl.starts_with('?') {
+            true => decl,
+            false => format!("?{}", decl)
+        }),
+        _ => return None
+    };
+    Some(decl.to_owned())
 }
 
-fn mk_cast_expr(builder: &AstBuilder, from: P<Expr>, to: P<Ty>) -> P<Expr> {
-    builder.expr().build_expr_kind(ExprKind::Cast(from, to))
+/// Whether `#[php_type]` can be declared: pseudo types of later PHP versions and unions would be taken for class names
+fn supported_type(decl: &str) -> bool {
+    let name = decl.trim_start_matches('?');
+    let pseudo = ["void", "null", "false", "true", "never", "iterable", "object", "resource", "self", "parent", "static"];
+    !name.is_empty() && !name.contains(&['|', '&', '?'][..]) && !pseudo.contains(&&*name.to_ascii_lowercase())
 }
 
-fn mk_ty_ptr(builder: &AstBuilder, ty: P<Ty>, mut_: Mutability) -> P<Ty> {
-    builder.ty().build_ty_kind(TyKind::Ptr(MutTy { ty: ty, mutbl: mut_ }))
+/// The PHP return type, `T` of `Result<T, E>` for fallible functions
+fn return_type(sig: &Signature) -> Option<String> {
+    match sig.output {
+        ReturnType::Default => None,
+        ReturnType::Type(_, ref ty) => match type_param(ty, "Result") {
+            Some(ty) => php_type(&ty),
+            None => php_type(ty)
+        }
+    }
 }
 
-fn mk_ty_sized_slice(builder: &AstBuilder, ty: P<Ty>, expr: P<Expr>) -> P<Ty> {
-    builder.ty().build_ty_kind(TyKind::FixedLengthVec(ty, expr))
+/// `Some("..")` or `None` tokens
+fn opt_str(value: Option<String>) -> TokenStream2 {
+    match value {
+        Some(value) => quote!(Some(#value)),
+        None => quote!(None)
+    }
 }
 
-/// Generate an AST expr for a function entry for the PHP module export
-fn mk_zend_function_entry(builder: &AstBuilder, func_param: Option<&RegisteredFunc>) -> P<Expr> {
-    let name_expr;
-    let handler_expr;
-    let arginfo_expr;
-    let num_args;
+/// The Rust value of `#[default = expr]`, string literals are accepted for String (and &str) parameters
+///
+/// Literals for `Option<T>` parameters are wrapped in `Some`, other expressions are used as they are.
+fn mk_default(expr: &Expr, ty: &Type) -> TokenStream2 {
+    let value = match *expr {
+        Expr::Lit(ExprLit { lit: Lit::Str(ref s), .. }) => quote!(::std::convert::From::from(#s)),
+        _ => quote!(#expr)
+    };
+    match *expr {
+        Expr::Lit(_) if is_type(ty, "Option") => quote!(Some(#value)),
+        _ => value
+    }
+}
 
-    match func_param {
-        None => {
-            name_expr = mk_null_ptr(builder);
-            arginfo_expr = mk_null_ptr(builder);
-            handler_expr = builder.expr().none();
-            num_args = 0;
+/// The function called by the engine (void zif_name(zend_execute_data *execute_data, zval *return_value)):
+/// converts the arguments, calls `call` and assigns its return value, panics must not unwind into the engine
+///
+/// The error of fallible functions (`Result<T, E>` where `PhpException: From<E>`) is thrown.
+fn mk_wrapper(wrapper: &Ident, call: TokenStream2, php_name: &str, args: &[FuncArg], context: Option<usize>, output: &ReturnType, deprecated: Option<&str>) -> TokenStream2 {
+    // the engine's notice (ZEND_ACC_DEPRECATED) has no room for a message
+    let deprecated = match deprecated {
+        Some(msg) => {
+            // a user error handler may throw, the function must not run then
+            quote! {
+                ::rustyphp::error::raise_deprecated_call(unsafe { &*_ex }, #php_name, #msg);
+                if ::rustyphp::PhpException::is_pending() {
+                    return
+                }
+            }
         },
-        Some(func) => {
-            //TODO: get ExprCast, ptr into aster
-            num_args = match func.args {
-                None => 0,
-                Some(ref x) => x.len() as u32
-            };
-            let name: Vec<_> = func.real_name.as_bytes().iter().chain(&[0u8]).cloned().collect();
-            name_expr = mk_lit_ptr_expr(&builder, name);
-            arginfo_expr = builder.expr().block().unsafe_().expr().build(mk_cast_expr(&builder, mk_cast_expr(&builder,
-                builder.expr().ref_().id(format!("ARG_INFO_{}", func.real_name)),
-                mk_ty_ptr(&builder, builder.ty().infer(), Mutability::Immutable)
-            ), mk_ty_ptr(&builder, builder.ty().infer(), Mutability::Mutable)));
-            handler_expr = builder.expr().some()
-                //skip 1 path item to ensure we do not try ::crate::mod which fails since ::crate doesn't work within the same crate
-                .path().global().ids(func.mod_path.iter().skip(1).chain(&[builder.id(&func.internal_name)])).build()
+        None => quote!()
+    };
+    // like internal functions: too few and (unless variadic) too many arguments are rejected
+    let min_args = Literal::usize_unsuffixed(required_args(args));
+    let verify = match args.last().map(|arg| arg.variadic.is_some()).unwrap_or(false) {
+        true => quote!(::rustyphp::verify_arg_count!(#php_name, unsafe { &*_ex }, #min_args, None);),
+        false => {
+            let max_args = Literal::usize_unsuffixed(args.len());
+            quote!(::rustyphp::verify_arg_count!(#php_name, unsafe { &*_ex }, #min_args, Some(#max_args));)
+        }
+    };
+    // convert_arg locates conversion errors by function, argument position and name
+    let mut conversions: Vec<TokenStream2> = args.iter().enumerate().map(|(idx, arg)| {
+        let name = &arg.name;
+        let ty = &arg.ty;
+        let idx = Literal::usize_unsuffixed(idx);
+        // the target type is explicit, inference through the generic conversions could recurse endlessly
+        if let Some(ref inner) = arg.variadic {
+            return quote!(::rustyphp::zend_try!(unsafe { ::rustyphp::convert_variadic::<#inner>(_ex, #idx, #name, #php_name) }));
         }
+        // every argument is a distinct slot of the call frame (derived from the raw frame pointer),
+        // so several of them can be borrowed (mutably) at once
+        let slot = quote!(unsafe { &mut *::rustyphp::types::execute_data::ExecuteData::arg_ptr(_ex, #idx) });
+        let convert = quote!(::rustyphp::zend_try!(::rustyphp::convert_arg::<#ty>(#slot, #idx, #name, #php_name)));
+        match arg.default {
+            None => convert,
+            // omitted arguments must not be read (the slots are not initialized)
+            Some(ref value) => quote!(match unsafe { (*_ex).arg_count() } > #idx {
+                true => #convert,
+                false => #value
+            })
+        }
+    }).collect();
+    if let Some(pos) = context {
+        // the context must not hand out the slots still borrowed by the converted arguments
+        let borrowed = mk_borrowed(args);
+        conversions.insert(pos, quote!(&mut unsafe { ::rustyphp::CallContext::new(_ex, _zv, #borrowed) }));
     }
-    builder.expr().struct_()
-        .id("ZendFunctionEntry").build()
-        .field("name").build(name_expr)
-        .field("handler").build(handler_expr)
-        .field("arg_info").build(arginfo_expr)
-        .field("num_args").build(builder.expr().u32(num_args))
-        .field("flags").build(builder.expr().u32(0))
-        .build()
-}
-
-// TODO: missing in aster: static NAME: ty = val;
-fn mk_static_item<T: ToIdent>(builder: &AstBuilder, name: T, ty: P<Ty>, mut_: Mutability, val: P<Expr>) -> P<Item> {
-    builder.item().build_item_kind(name, ItemKind::Static(ty, mut_, val))
-}
-
-fn mk_lit_ptr_expr(builder: &AstBuilder, bytes: Vec<u8>) -> P<Expr> {
-    mk_cast_expr(&builder,
-        builder.expr().lit().byte_str(bytes),
-        mk_ty_ptr(&builder, builder.ty().infer(), Mutability::Immutable)
-    )
-}
-
-/// Macro: Get a list of registered PHP functions (used to generate the DLL exports)
-fn get_php_funcs<'cx>(ectx: &'cx mut ExtCtxt, span: Span, _: &[TokenTree]) -> Box<MacResult + 'cx> {
-    let mut funcs: Vec<RegisteredFunc> = vec![];
-    REGISTERED_FUNCS.with(|rf| {
-        let fn_data = &*rf.borrow();
-        funcs = fn_data.iter().map(|fn_| fn_.clone()).collect();
-    });
-
-    // we do not really check if it's actually length thats asked yet
-    let builder = AstBuilder::new();
+    let call = quote!(#call(#(#conversions),*));
+    let assign_ret = quote!(::rustyphp::zend_try_option!(::rustyphp::AssignTo::assign_to(&_ret, unsafe { &mut *_zv })););
+    let (call, assign_ret) = match *output {
+        ReturnType::Default => (call, quote!()),
+        ReturnType::Type(_, ref ty) => match type_param(ty, "Result") {
+            Some(ref ok) if is_unit(ok) => (quote!(::rustyphp::zend_try!(#call)), quote!()),
+            Some(_) => (quote!(::rustyphp::zend_try!(#call)), assign_ret),
+            None if is_unit(ty) => (call, quote!()),
+            None => (call, assign_ret)
+        }
+    };
+    // Variables prefixed with _ since we do (and sometimes cannot) check if they actually are used
+    quote! {
+        #[doc(hidden)]
+        pub extern "C" fn #wrapper(_ex: *mut ::rustyphp::types::execute_data::ExecuteData, _zv: *mut ::rustyphp::Zval) {
+            ::rustyphp::unwind::guard(#php_name, || {
+                #deprecated
+                #verify
+                let _ret = #call;
+                #assign_ret
+            });
+        }
+    }
+}
 
-    // We assume that this is only used on module initialization, so we use this to trigger
-    // our module initialized state
-    ALREADY_COMPILED.with(|rf| {
-        let mut already_compiled = rf.borrow_mut();
-        if *already_compiled {
-            ectx.span_err(span, "`get_php_funcs` cannot be called multiple times since it's used to detect multiple php_ext declarations by `php_ext`");
+/// `fn(usize) -> bool` telling whether the parameters borrow an argument slot
+fn mk_borrowed(args: &[FuncArg]) -> TokenStream2 {
+    let mut slots = vec![];
+    let mut rest = None;
+    for (idx, arg) in args.iter().enumerate() {
+        match arg.variadic {
+            Some(ref inner) if borrows(inner) => rest = Some(Literal::usize_unsuffixed(idx)),
+            Some(_) => {},
+            None if borrows(&arg.ty) => slots.push(Literal::usize_unsuffixed(idx)),
+            None => {}
         }
-        *already_compiled = true;
+    }
+    let rest = match rest {
+        Some(first) => quote!(_idx >= #first),
+        None => quote!(false)
+    };
+    match slots.is_empty() {
+        true => quote!(|_idx| #rest),
+        false => quote!(|_idx| match _idx { #(#slots)|* => true, _ => #rest })
+    }
+}
+
+/// Whether a converted value may keep borrowing its zval (references, lifetimes, `ByRef`)
+fn borrows(ty: &Type) -> bool {
+    match *ty {
+        Type::Path(ref path) => path.path.segments.iter().any(|seg| seg.ident == "ByRef" || match seg.arguments {
+            PathArguments::AngleBracketed(ref generics) => generics.args.iter().any(|arg| match *arg {
+                GenericArgument::Lifetime(_) => true,
+                GenericArgument::Type(ref ty) => borrows(ty),
+                _ => false
+            }),
+            _ => false
+        }),
+        Type::Tuple(ref tuple) => tuple.elems.iter().any(borrows),
+        Type::Paren(ref paren) => borrows(&paren.elem),
+        Type::Group(ref group) => borrows(&group.elem),
+        // references, and anything unusual to be safe
+        _ => true
+    }
+}
+
+/// The options of #[php_func(...)]
+struct FuncOptions {
+    /// Without leading and trailing backslashes
+    namespace: Option<String>,
+    /// The PHP name if it differs from the Rust one
+    name: Option<String>,
+    /// Additional names (in the same namespace), true if deprecated
+    aliases: Vec<(String, bool)>,
+    /// The ZEND_ACC_* flags (names of the `rustyphp::ffi` constants)
+    flags: Vec<&'static str>,
+    /// `deprecated = "..."`
+    deprecated_message: Option<String>
+}
+
+/// `ffi::A | ffi::B` (0 for none)
+fn mk_flags(flags: &[&str]) -> TokenStream2 {
+    if flags.is_empty() {
+        return quote!(0)
+    }
+    let flags = flags.iter().map(|flag| {
+        let flag = Ident::new(flag, Span::call_site());
+        quote!(::rustyphp::ffi::#flag)
     });
-    let mut expr_ = builder.expr().slice();
-    let func_slice_len = funcs.len() + 1;
-
-    let mut item_vec = Vec::with_capacity(func_slice_len);
-    for func in funcs {
-        expr_ = expr_.expr().build(mk_zend_function_entry(&builder, Some(&func)));
-        // Build argument info
-        // TODO: Make name generation include mod (ensure unique)
-        // static MUT FUNC_ARG_INFO: ZendInternalArgInfo = {...}
-        let arginfo_path = builder.path().id("ZendInternalArgInfo").build();
-
-        let mut slice_builder = builder
-            .expr().slice()
-            // Header Building
-            .expr().struct_path(arginfo_path.clone())
-                .field("arg_name").build(mk_cast_expr(&builder, builder.expr().u32(func.required_args as u32), mk_ty_ptr(&builder, builder.ty().u8(), Mutability::Immutable)))
-                .field("cls_name").build(mk_null_ptr(&builder))
-                .field("type_hint").u8(0) //TODO
-                .field("pass_by_ref").u8(0)
-                .field("allow_null").bool(false)
-                .field("is_variadic").bool(false)
-                .build();
-
-        if func.args.is_some() {
-            for param in func.args.unwrap() {
-                let arg_name: Vec<_> = match param.pat.node {
-                    PatKind::Ident(_, arg_id, _) => arg_id.node.name.as_str().as_bytes().iter().chain(&[0u8]).cloned().collect(),
-                    _ => panic!("Unexpected type for arg pattern")
+    quote!(#(#flags)|*)
+}
+
+/// A valid PHP label (function name or namespace segment)
+fn is_label(name: &str) -> bool {
+    name.chars().next().map(|c| c.is_alphabetic() || c == '_').unwrap_or(false) && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Read `namespace`, `name`, `alias` and `deprecated_alias` of #[php_func(...)]
+fn parse_func_meta(args: &[NestedMeta]) -> syn::Result<FuncOptions> {
+    let mut opts = FuncOptions { namespace: None, name: None, aliases: vec![], flags: vec![], deprecated_message: None };
+    for arg in args {
+        let nv = match *arg {
+            NestedMeta::Meta(Meta::NameValue(ref nv)) => nv,
+            NestedMeta::Meta(Meta::Path(ref path)) => {
+                let flag = match path.get_ident().map(|ident| ident.to_string()) {
+                    Some(ref flag) if flag == "deprecated" => "ZEND_ACC_DEPRECATED",
+                    Some(ref flag) if flag == "return_ref" => "ZEND_ACC_RETURN_REFERENCE",
+                    // ZEND_ACC_COMPILE_TIME_EVAL (PHP >= 8.2) is another flag in the PHP 7 layout
+                    Some(ref flag) if flag == "compile_time_eval" => return Err(syn::Error::new(path.span(), "php_func: `compile_time_eval` requires PHP >= 8.2, which isn't supported yet")),
+                    _ => return Err(syn::Error::new(path.span(), "php_func: unknown flag, expected `deprecated` or `return_ref`"))
                 };
-                let name_expr = mk_lit_ptr_expr(&builder, arg_name);
-                slice_builder = slice_builder
-                    .expr().struct_path(arginfo_path.clone())
-                        .field("arg_name").build(name_expr)
-                        .field("cls_name").build(mk_null_ptr(&builder))
-                        .field("type_hint").u8(0)
-                        .field("pass_by_ref").u8(0)
-                        .field("allow_null").bool(true)
-                        .field("is_variadic").bool(false)
-                        .build();
+                opts.flags.push(flag);
+                continue;
+            },
+            ref other => return Err(syn::Error::new(other.span(), "php_func: expected key = \"value\" or a flag"))
+        };
+        let value = match nv.lit {
+            Lit::Str(ref s) => s.value(),
+            ref lit => return Err(syn::Error::new(lit.span(), "php_func: expected a string literal"))
+        };
+        if nv.path.is_ident("deprecated") {
+            opts.deprecated_message = Some(value);
+            continue;
+        }
+        if nv.path.is_ident("namespace") {
+            let namespace = value.trim_matches('\\');
+            if !namespace.split('\\').all(is_label) {
+                return Err(syn::Error::new(nv.lit.span(), format!("php_func: invalid namespace `{}`", value)));
             }
+            opts.namespace = Some(namespace.to_owned());
+            continue;
+        }
+        if !is_label(&value) {
+            return Err(syn::Error::new(nv.lit.span(), format!("php_func: invalid function name `{}`", value)));
+        }
+        if nv.path.is_ident("name") {
+            opts.name = Some(value);
+        } else if nv.path.is_ident("alias") {
+            opts.aliases.push((value, false));
+        } else if nv.path.is_ident("deprecated_alias") {
+            opts.aliases.push((value, true));
+        } else {
+            return Err(syn::Error::new(nv.path.span(), "php_func: unknown option, expected `namespace`, `name`, `alias`, `deprecated_alias` or `deprecated`"));
         }
-        let arg_info_slice = slice_builder.build();
-
-        item_vec.push(mk_static_item(
-            &builder,
-            format!("ARG_INFO_{}", func.real_name),
-            mk_ty_sized_slice(&builder, builder.ty().build_path(arginfo_path), builder.expr().usize(func.required_args as usize + 1)),
-            Mutability::Mutable,
-            arg_info_slice
-        ));
     }
-    let func_slice_expr = builder.expr().build(expr_.expr().build(mk_zend_function_entry(&builder, None)).build());
-    // static mut FUNC_PTR: [$crate::ZendFunctionEntry; func_len] = [...]
-
-    //TODO: get TyKind::FixedLengthVec into aster
-    let func_ptr_item = mk_static_item(&builder, "FUNC_PTR",
-        mk_ty_sized_slice(&builder,
-            builder.ty().path().id("ZendFunctionEntry").build(),
-            builder.expr().usize(func_slice_len)
-        ),
-        Mutability::Mutable,
-        func_slice_expr
-    );
-
-    // println!("{}", syntax::print::pprust::expr_to_string(&expr));
-    item_vec.push(func_ptr_item);
-
-    let items = SmallVector::many(item_vec);
-    MacEager::items(items)
-}
-
-// Macro: get a list of registered classes
-fn get_php_classes<'cx>(ectx: &'cx mut ExtCtxt, span: Span, _: &[TokenTree]) -> Box<MacResult + 'cx> {
-    // We assume that this is only used on module initialization, so we use this to trigger
-    // our module initialized state
-    let mut clss: Vec<RegisteredClass> = vec![];
-    REGISTERED_CLS.with(|rf| {
-        let cls_data = &*rf.borrow();
-        clss = cls_data.iter().map(|cls_| cls_.clone()).collect();
-    });
-    println!("{:?}", clss);
-
-    let builder = AstBuilder::new();
-    let mut stmt_vec = Vec::with_capacity(clss.len());
-    for cls in clss {
-        let macro_expr = builder.stmt().build_expr(mk_macro_expr(&builder,
-            builder.item().mac().path().id("zend_define_class").build().expr().lit().str(intern(&cls.name)).build()
-        ));
-        stmt_vec.push(macro_expr);
+    if opts.deprecated_message.is_some() && opts.flags.contains(&"ZEND_ACC_DEPRECATED") {
+        return Err(syn::Error::new(Span::call_site(), "php_func: use either `deprecated` or `deprecated = \"...\"`"));
     }
+    Ok(opts)
+}
 
-    let stmts = SmallVector::many(stmt_vec);
-    MacEager::stmts(stmts)
+/// Read `name = "..."` and `extends = "..."` of #[php_exception(...)]
+fn parse_exception_meta(args: &[NestedMeta]) -> syn::Result<(Option<String>, Option<String>)> {
+    let mut name = None;
+    let mut extends = None;
+    for arg in args {
+        let nv = match *arg {
+            NestedMeta::Meta(Meta::NameValue(ref nv)) => nv,
+            ref other => return Err(syn::Error::new(other.span(), "php_exception: expected key = \"value\""))
+        };
+        let value = match nv.lit {
+            Lit::Str(ref s) => s.value(),
+            ref lit => return Err(syn::Error::new(lit.span(), "php_exception: expected a string literal"))
+        };
+        if nv.path.is_ident("name") {
+            name = Some(value);
+        } else if nv.path.is_ident("extends") {
+            extends = Some(value);
+        } else {
+            return Err(syn::Error::new(nv.path.span(), "php_exception: unknown option, expected `name` or `extends`"));
+        }
+    }
+    Ok((name, extends))
+}
+
+fn expand_php_exception(args: AttributeArgs, item: Item) -> syn::Result<TokenStream2> {
+    let mut enum_ = match item {
+        Item::Enum(enum_) => enum_,
+        other => return Err(syn::Error::new(other.span(), "`php_exception` can only be used on enums"))
+    };
+    if !enum_.generics.params.is_empty() {
+        return Err(syn::Error::new(enum_.generics.span(), "php_exception: generic enums are not supported"));
+    }
+    let (name, extends) = parse_exception_meta(&args)?;
+    let enum_name = enum_.ident.clone();
+    let class_name = name.unwrap_or_else(|| enum_name.to_string());
+
+    // The statics hold the class entries (set during MINIT)
+    let base_ce = format_ident!("__RUSTYPHP_CE_{}", enum_name);
+    let mut statics = vec![base_ce.clone()];
+    let mut arms = vec![];
+    let mut variant_classes = vec![];
+    for variant in enum_.variants.iter_mut() {
+        // variant attributes are consumed here (they'd be unknown attributes else)
+        let mut variant_class = None;
+        let mut attrs = vec![];
+        for attr in variant.attrs.drain(..) {
+            if !attr.path.is_ident("php_exception") {
+                attrs.push(attr);
+                continue;
+            }
+            let args: Vec<NestedMeta> = match attr.parse_meta()? {
+                Meta::List(list) => list.nested.into_iter().collect(),
+                other => return Err(syn::Error::new(other.span(), "php_exception: expected #[php_exception(name = \"...\")]"))
+            };
+            variant_class = parse_exception_meta(&args)?.0;
+        }
+        variant.attrs = attrs;
+        let variant_class = match variant_class {
+            Some(variant_class) => variant_class,
+            None => continue
+        };
+        let ident = &variant.ident;
+        let ce = format_ident!("__RUSTYPHP_CE_{}_{}", enum_name, ident);
+        // match any value of the variant
+        let pattern = match variant.fields {
+            Fields::Unit => quote!(),
+            Fields::Unnamed(_) => quote!((..)),
+            Fields::Named(_) => quote!({ .. })
+        };
+        arms.push(quote!(&#enum_name::#ident #pattern => #ce,));
+        variant_classes.push(quote!(#ce = ::rustyphp::define_class(#variant_class, #base_ce);));
+        statics.push(ce);
+    }
+    // a missing parent fails MINIT instead of silently extending Exception
+    let parent = match extends {
+        None => quote!(::rustyphp::ExceptionClass::Exception.class_entry()),
+        Some(ref parent) => {
+            let err = format!("{}: parent class {} not found", class_name, parent);
+            quote!(match ::rustyphp::ExceptionClass::Named(#parent.to_owned()).find_class_entry() {
+                Some(ce) => ce,
+                None => return Err(#err.to_owned())
+            })
+        }
+    };
+    let extends = match extends {
+        None => quote!(None),
+        Some(ref parent) => quote!(Some(#parent))
+    };
+    Ok(quote! {
+        #enum_
+
+        #(
+            #[doc(hidden)]
+            #[allow(non_upper_case_globals)]
+            static mut #statics: *mut ::rustyphp::ZendClassEntry = 0 as *mut ::rustyphp::ZendClassEntry;
+        )*
+
+        impl ::std::convert::From<#enum_name> for ::rustyphp::PhpException {
+            #[allow(unreachable_patterns)]
+            fn from(err: #enum_name) -> ::rustyphp::PhpException {
+                let ce = unsafe { match &err { #(#arms)* _ => #base_ce } };
+                ::rustyphp::PhpException::new(format!("{}", err)).class(::rustyphp::ExceptionClass::Entry(ce))
+            }
+        }
+
+        // the enum class first, since it's the parent of the variant classes
+        ::rustyphp::inventory::submit! {
+            ::rustyphp::PhpClass {
+                name: #class_name,
+                extends: #extends,
+                register: {
+                    unsafe fn register() -> ::std::result::Result<(), ::std::string::String> {
+                        #base_ce = ::rustyphp::define_class(#class_name, #parent);
+                        #(#variant_classes)*
+                        Ok(())
+                    }
+                    register
+                }
+            }
+        }
+    })
 }
@@ -1,16 +1,41 @@
+extern crate cc;
+
 use std::env;
 use std::fs::File;
 use std::io::{Write, BufWriter};
 use std::path::Path;
+use std::process::Command;
+
+/// The include flags of the PHP headers (`PHP_INCLUDES` or `php-config --includes`)
+fn php_includes() -> Vec<String> {
+    let flags = match env::var("PHP_INCLUDES") {
+        Ok(flags) => flags,
+        Err(_) => {
+            let output = Command::new("php-config").arg("--includes").output()
+                .expect("PHP headers not found: set PHP_INCLUDES to the output of `php-config --includes`");
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+    };
+    flags.split_whitespace().map(|flag| flag.trim_start_matches("-I").to_owned()).collect()
+}
 
 fn main() {
     //TODO: Buildscript for config stuff (...)
     println!("cargo:rustc-link-lib=dylib=php7_debug"); //TODO: Determine name
-    println!("cargo:rustc-link-search={}", "..\\..\\php7\\x64\\Debug"); //TODO
+    println!("cargo:rustc-link-search=..\\..\\php7\\x64\\Debug"); //TODO
+
+    // C shims for engine macros (zend_try/zend_catch, EG())
+    let mut shim = cc::Build::new();
+    shim.file("src/shim.c");
+    for include in php_includes() {
+        shim.include(include);
+    }
+    shim.compile("rustyphp_shim");
+    println!("cargo:rerun-if-changed=src/shim.c");
 
     let path = env::var_os("OUT_DIR").unwrap();
     let path: &Path = path.as_ref();
     let path = path.join("test_helper.rs");
     let mut file = BufWriter::new(File::create(&path).unwrap());
-    write!(file, "pub static PHP_PATH: &'static str = \"{}\";", "../../php7/x64/Debug/php.exe").unwrap(); //TODO
+    write!(file, "pub static PHP_PATH: &str = \"../../php7/x64/Debug/php.exe\";").unwrap(); //TODO
 }
@@ -0,0 +1,77 @@
+//! Raise PHP errors (warnings, notices, ...) like the built-in functions do
+//!
+//! ```ignore
+//! if path.is_empty() {
+//!     php_warning!("Path cannot be empty");
+//!     return false
+//! }
+//! ```
+//!
+//! Messages are prefixed with the name of the running function and go through the
+//! regular error handling, so `error_reporting`, `@` and `set_error_handler()` apply.
+use std::ffi::CString;
+use std::panic;
+use std::process;
+use types::c_int;
+use types::execute_data::ExecuteData;
+use ffi;
+use unwind;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorLevel {
+    /// E_ERROR, aborts the request
+    Error = 1,
+    Warning = 2,
+    Notice = 8,
+    /// E_CORE_WARNING, raised during startup (MINIT)
+    CoreWarning = 32,
+    Deprecated = 8192
+}
+
+/// Unwind payload of `php_error!`, raised as E_ERROR by `unwind::guard` once the Rust frames are dropped
+#[derive(Debug)]
+pub struct FatalError(pub String);
+
+/// The C string passed to the engine's error functions, interior NUL bytes (which would truncate
+/// the message) are escaped as `\0`
+pub fn c_message(msg: &str) -> CString {
+    CString::new(msg.replace('\0', "\\0")).unwrap_or_default()
+}
+
+/// Raise an error of the given level (php_error_docref)
+///
+/// `ErrorLevel::Error` bails out of the request without returning, values owned by the calling
+/// frames are not dropped. Use `fatal` (or `php_error!`) inside functions instead.
+pub fn raise(level: ErrorLevel, msg: &str) {
+    let msg = c_message(msg);
+    unsafe { ffi::rustyphp_error(level as c_int, msg.as_ptr()) };
+}
+
+/// Raise an error without the function name prefix (zend_error), e.g. for messages naming the function themselves
+pub fn raise_unprefixed(level: ErrorLevel, msg: &str) {
+    let msg = c_message(msg);
+    unsafe { ffi::zend_error(level as c_int, b"%s\0".as_ptr() as *const _, msg.as_ptr()) };
+}
+
+/// E_DEPRECATED for a call of the running function with a hint like "use foo() instead"
+///
+/// The function is named as it was called (aliases share the wrapper), `fallback` if the frame has no name.
+pub fn raise_deprecated_call(ex: &ExecuteData, fallback: &str, hint: &str) {
+    let name = ex.function_name();
+    let name = name.as_ref().map(|name| &name[..]).unwrap_or(fallback);
+    raise_unprefixed(ErrorLevel::Deprecated, &format!("Function {}() is deprecated, {}", name, hint));
+}
+
+/// Abort the request with a fatal error after unwinding to the function wrapper
+///
+/// Unwinding stops at `unwind::guard`, which every wrapper and lifecycle hook runs in.
+/// Outside of guarded code the error is raised right away like `raise` does (nothing may unwind into the engine).
+pub fn fatal(msg: String) -> ! {
+    if !unwind::is_guarded() {
+        raise(ErrorLevel::Error, &msg);
+        // E_ERROR bails out, this is only reached without a running engine
+        process::abort()
+    }
+    // resume_unwind skips the panic hook, this is no bug to be reported
+    panic::resume_unwind(Box::new(FatalError(msg)))
+}
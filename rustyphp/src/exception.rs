@@ -0,0 +1,359 @@
+//! Throwing and catching PHP exceptions
+use std::cell::Cell;
+use std::fmt;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use php_config::*;
+use types::*;
+use types::zstr::ZendStr;
+use zend_mm::Refcounted;
+use zend_module::ZendClassEntry;
+use ffi;
+use unwind;
+
+thread_local!(static PENDING_BAILOUT: Cell<bool> = const { Cell::new(false) });
+
+/// The class of a thrown exception
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExceptionClass {
+    Exception,
+    Error,
+    TypeError,
+    /// PHP >= 7.1
+    ArgumentCountError,
+    /// PHP >= 8.0
+    ValueError,
+    // SPL
+    LogicException,
+    BadFunctionCallException,
+    BadMethodCallException,
+    DomainException,
+    InvalidArgumentException,
+    LengthException,
+    OutOfRangeException,
+    RuntimeException,
+    OutOfBoundsException,
+    OverflowException,
+    RangeException,
+    UnderflowException,
+    UnexpectedValueException,
+    /// Any class known to the engine, looked up by its (fully qualified) name
+    Named(String),
+    /// A class entry, e.g. as returned from `zend_define_class!`
+    Entry(*mut ZendClassEntry)
+}
+
+impl ExceptionClass {
+    /// The PHP class name (None for raw class entries)
+    pub fn name(&self) -> Option<&str> {
+        Some(match *self {
+            ExceptionClass::Exception => "Exception",
+            ExceptionClass::Error => "Error",
+            ExceptionClass::TypeError => "TypeError",
+            ExceptionClass::ArgumentCountError => "ArgumentCountError",
+            ExceptionClass::ValueError => "ValueError",
+            ExceptionClass::LogicException => "LogicException",
+            ExceptionClass::BadFunctionCallException => "BadFunctionCallException",
+            ExceptionClass::BadMethodCallException => "BadMethodCallException",
+            ExceptionClass::DomainException => "DomainException",
+            ExceptionClass::InvalidArgumentException => "InvalidArgumentException",
+            ExceptionClass::LengthException => "LengthException",
+            ExceptionClass::OutOfRangeException => "OutOfRangeException",
+            ExceptionClass::RuntimeException => "RuntimeException",
+            ExceptionClass::OutOfBoundsException => "OutOfBoundsException",
+            ExceptionClass::OverflowException => "OverflowException",
+            ExceptionClass::RangeException => "RangeException",
+            ExceptionClass::UnderflowException => "UnderflowException",
+            ExceptionClass::UnexpectedValueException => "UnexpectedValueException",
+            ExceptionClass::Named(ref name) => name,
+            ExceptionClass::Entry(_) => return None
+        })
+    }
+
+    /// Resolve the class entry, unknown classes (e.g. ValueError before PHP 8) and classes
+    /// which can't be thrown (no Throwable, interfaces, abstract classes) fall back to `Exception`
+    ///
+    /// `ArgumentCountError` was added in PHP 7.1, PHP 7.0 throws a `TypeError` for missing arguments
+    /// to user functions, so that's used instead.
+    pub fn class_entry(&self) -> *mut ZendClassEntry {
+        match self.find_class_entry() {
+            Some(ce) if unsafe { ffi::rustyphp_is_throwable(ce, 1) } != 0 => ce,
+            None if *self == ExceptionClass::ArgumentCountError => unsafe { ffi::zend_ce_type_error },
+            _ => unsafe { ffi::zend_ce_exception }
+        }
+    }
+
+    /// Resolve the class entry, None if the class doesn't exist or isn't a Throwable class
+    ///
+    /// Named classes are only looked up in the class table, this never triggers autoloading.
+    pub fn find_class_entry(&self) -> Option<*mut ZendClassEntry> {
+        unsafe {
+            let ce = match *self {
+                ExceptionClass::Exception => ffi::zend_ce_exception,
+                ExceptionClass::Error => ffi::zend_ce_error,
+                ExceptionClass::TypeError => ffi::zend_ce_type_error,
+                ExceptionClass::Entry(ce) => ce,
+                _ => {
+                    let name = self.name().unwrap_or("Exception");
+                    ffi::rustyphp_find_class(name.as_ptr() as *const _, name.len())
+                }
+            };
+            match !ce.is_null() && ffi::rustyphp_is_throwable(ce, 0) != 0 {
+                true => Some(ce),
+                false => None
+            }
+        }
+    }
+}
+
+/// A PHP exception to be thrown
+///
+/// ```ignore
+/// PhpException::new("negative radius")
+///     .class(ExceptionClass::InvalidArgumentException)
+///     .code(42)
+///     .throw();
+/// ```
+#[derive(Debug, Clone)]
+pub struct PhpException {
+    pub class: ExceptionClass,
+    pub message: String,
+    pub code: zend_long,
+    pub previous: Option<Box<PhpException>>,
+    /// The exception object, if it was thrown by PHP (see `try_catch`)
+    pub object: Option<Refcounted<ZvalValueObject>>,
+    /// PHP bailed out (fatal error, exit()), see `try_catch`
+    pub bailout: bool
+}
+
+impl PhpException {
+    pub fn new<S: Into<String>>(message: S) -> PhpException {
+        PhpException {
+            class: ExceptionClass::Exception,
+            message: message.into(),
+            code: 0,
+            previous: None,
+            object: None,
+            bailout: false
+        }
+    }
+
+    /// Take the pending exception (EG(exception)) if there is one
+    pub fn take_pending() -> Option<PhpException> {
+        unsafe {
+            let mut info: ffi::ExceptionInfo = mem::zeroed();
+            if ffi::rustyphp_take_exception(&mut info) == 0 {
+                return None
+            }
+            let class_name = ZendStr::from_raw(info.class_name);
+            let message = ZendStr::from_raw(info.message);
+            Some(PhpException {
+                class: ExceptionClass::Named(String::from_utf8_lossy(class_name.as_bytes()).into_owned()),
+                message: String::from_utf8_lossy(message.as_bytes()).into_owned(),
+                code: info.code,
+                previous: None,
+                object: Some(Refcounted::from_raw(info.object)),
+                bailout: false
+            })
+        }
+    }
+
+    /// Whether an exception is pending (EG(exception)), e.g. thrown by a user error handler
+    #[inline]
+    pub fn is_pending() -> bool {
+        unsafe { ffi::rustyphp_has_exception() != 0 }
+    }
+
+    #[inline]
+    pub fn class(mut self, class: ExceptionClass) -> PhpException {
+        self.class = class;
+        self
+    }
+
+    #[inline]
+    pub fn message<S: Into<String>>(mut self, message: S) -> PhpException {
+        self.message = message.into();
+        self
+    }
+
+    #[inline]
+    pub fn code(mut self, code: zend_long) -> PhpException {
+        self.code = code;
+        self
+    }
+
+    #[inline]
+    pub fn previous(mut self, previous: PhpException) -> PhpException {
+        self.previous = Some(Box::new(previous));
+        self
+    }
+
+    /// Throw the exception (sets EG(exception)), the caller has to return to the engine afterwards
+    pub fn throw(&self) {
+        if self.bailout {
+            // re-raised once the Rust frames are unwound (see `unwind::guard`)
+            defer_bailout();
+            return;
+        }
+        if let Some(ref object) = self.object {
+            // rethrow the original object (keeps trace, previous, custom properties)
+            // zend_throw_exception_object takes over the reference held by zv
+            let mut zv = Zval::new();
+            unsafe { zv.value.as_ptr_mut().data = Refcounted::into_raw(object.clone()) as *mut _ };
+            zv.set_type(ZvalType::Object);
+            unsafe { ffi::zend_throw_exception_object(&mut zv) };
+            return;
+        }
+        // the engine chains an exception thrown while another one is pending as its previous
+        if let Some(ref previous) = self.previous {
+            previous.throw();
+        }
+        // the message is passed as zend_string, it may contain NUL bytes
+        let message = ZendStr::into_raw(ZendStr::from_str(&self.message));
+        unsafe {
+            ffi::rustyphp_throw_exception(self.class.class_entry(), message, self.code);
+        }
+    }
+}
+
+impl fmt::Display for PhpException {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.class.name() {
+            Some(name) => write!(f, "{}: {}", name, self.message),
+            None => write!(f, "{}", self.message)
+        }
+    }
+}
+
+impl<'a> From<&'a str> for PhpException {
+    fn from(message: &'a str) -> PhpException {
+        PhpException::new(message)
+    }
+}
+
+impl From<String> for PhpException {
+    fn from(message: String) -> PhpException {
+        PhpException::new(message)
+    }
+}
+
+/// Conversion failures are thrown as `TypeError` like the engine does for mismatching arguments
+impl From<ConversionError> for PhpException {
+    fn from(err: ConversionError) -> PhpException {
+        let class = match err.is_type_error() {
+            true => ExceptionClass::TypeError,
+            false => ExceptionClass::Exception
+        };
+        PhpException::new(err.to_string()).class(class)
+    }
+}
+
+/// The `ArgumentCountError` (thrown as `TypeError` on PHP 7.0) of a call passing `given` arguments to a function taking
+/// `min` to `max` (None if variadic) arguments, worded like the engine's own errors
+pub fn arg_count_error(function: &str, given: usize, min: usize, max: Option<usize>) -> PhpException {
+    let (bound, expected) = match max {
+        Some(max) if max == min => ("exactly", min),
+        Some(max) if given > max => ("at most", max),
+        _ => ("at least", min)
+    };
+    let plural = match expected {
+        1 => "",
+        _ => "s"
+    };
+    PhpException::new(format!("{}() expects {} {} argument{}, {} given", function, bound, expected, plural, given))
+        .class(ExceptionClass::ArgumentCountError)
+}
+
+/// Run `func` catching PHP exceptions and bailouts (zend_try/zend_catch)
+///
+/// Use this around calls back into the engine (user callbacks, property handlers, ...).
+/// A bailout skips the frames between the engine and `func`, so `func` itself should not own
+/// values with destructors. The bailout is re-raised after the Rust frames of the calling
+/// function wrapper are unwound.
+pub fn try_catch<F: FnOnce() -> R, R>(func: F) -> Result<R, PhpException> {
+    let mut func = Some(func);
+    let mut ret = None;
+    let bailout = {
+        let mut call = || {
+            let func = func.take().unwrap();
+            // a panic must not unwind through the setjmp frame of the shim
+            ret = Some(panic::catch_unwind(AssertUnwindSafe(func)));
+        };
+        unsafe { ffi::rustyphp_try_catch(try_catch_trampoline::<_>(&call), &mut call as *mut _ as *mut _) }
+    };
+    if bailout != 0 {
+        let mut ex = PhpException::new("PHP bailed out");
+        ex.bailout = true;
+        defer_bailout();
+        return Err(ex)
+    }
+    let ret = match ret.unwrap() {
+        Ok(ret) => ret,
+        Err(payload) => panic::resume_unwind(payload)
+    };
+    match PhpException::take_pending() {
+        Some(ex) => Err(ex),
+        None => Ok(ret)
+    }
+}
+
+/// Get the C callback for the closure type `F`
+fn try_catch_trampoline<F: FnMut()>(_: &F) -> extern "C" fn(*mut c_void) {
+    extern "C" fn trampoline<F: FnMut()>(data: *mut c_void) {
+        let func = unsafe { &mut *(data as *mut F) };
+        func();
+    }
+    trampoline::<F>
+}
+
+/// Record a caught bailout, to be re-raised by the enclosing `unwind::guard`
+///
+/// Outside of a guard nobody would re-raise it, the caller handles the returned `PhpException` instead.
+fn defer_bailout() {
+    if unwind::is_guarded() {
+        PENDING_BAILOUT.with(|pending| pending.set(true));
+    }
+}
+
+/// Take the bailout recorded by `try_catch`, `unwind::guard` scopes it to its own call
+pub fn take_pending_bailout() -> bool {
+    PENDING_BAILOUT.with(|pending| pending.replace(false))
+}
+
+/// Restore the bailout pending in an enclosing guard
+pub fn restore_pending_bailout(pending: bool) {
+    PENDING_BAILOUT.with(|cell| cell.set(pending));
+}
+
+/// Re-raise a bailout caught by `try_catch` (called once the Rust frames are unwound)
+pub fn reraise_bailout() {
+    unsafe { ffi::_zend_bailout(concat!(file!(), "\0").as_ptr() as *mut _, line!()) }
+}
+
+#[test]
+fn test_builder() {
+    let ex = PhpException::new("inner").class(ExceptionClass::TypeError);
+    let ex = PhpException::new("outer").class(ExceptionClass::Named("MyExt\\ConnectionException".to_owned())).code(7).previous(ex);
+    assert_eq!(ex.code, 7);
+    assert_eq!(format!("{}", ex), "MyExt\\ConnectionException: outer");
+    assert_eq!(format!("{}", ex.previous.unwrap()), "TypeError: inner");
+    assert_eq!(ExceptionClass::Entry(::std::ptr::null_mut()).name(), None);
+}
+
+#[test]
+fn test_arg_count_error() {
+    assert_eq!(arg_count_error("f", 0, 1, Some(1)).message, "f() expects exactly 1 argument, 0 given");
+    assert_eq!(arg_count_error("f", 1, 2, None).message, "f() expects at least 2 arguments, 1 given");
+    assert_eq!(arg_count_error("f", 0, 1, Some(3)).message, "f() expects at least 1 argument, 0 given");
+    assert_eq!(arg_count_error("f", 4, 1, Some(3)).message, "f() expects at most 3 arguments, 4 given");
+    assert_eq!(arg_count_error("f", 4, 1, Some(3)).class, ExceptionClass::ArgumentCountError);
+}
+
+#[test]
+fn test_bailout_outside_guard() {
+    let mut ex = PhpException::new("PHP bailed out");
+    ex.bailout = true;
+    ex.throw();
+    // nothing would re-raise it, so it must not leak into the next guarded call
+    assert!(!take_pending_bailout());
+}
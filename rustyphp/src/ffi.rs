@@ -1,32 +1,99 @@
-use libc::{c_long, c_uint};
+use libc::c_uint;
 use php_config::*;
 use zend_module::*;
 use super::types::*;
+use super::types::zstr::CZendString;
+use super::types::execute_data::ExecuteData;
+use zend_mm::ZendRefcounted;
 
-extern {
-    pub fn zend_throw_exception(ce: *mut c_void, msg: *mut c_char, code: c_long);
+/// Error levels
+pub const E_ERROR: c_int = 1;
+pub const E_WARNING: c_int = 2;
+pub const E_NOTICE: c_int = 8;
+pub const E_DEPRECATED: c_int = 8192;
+
+/// zend_function_entry.flags (PHP 7 values)
+pub const ZEND_ACC_DEPRECATED: u32 = 0x40000;
+pub const ZEND_ACC_RETURN_REFERENCE: u32 = 0x4000000;
+
+extern "C" {
+    pub fn zend_error(ty: c_int, format: *const c_char, ...);
+    pub fn zend_throw_exception(ce: *mut ZendClassEntry, msg: *const c_char, code: zend_long) -> *mut ZvalValueObject;
+    pub fn zend_throw_exception_object(exception: *mut Zval);
+    pub fn zend_lookup_class(name: *mut CZendString) -> *mut ZendClassEntry;
+    pub static zend_ce_exception: *mut ZendClassEntry;
+    pub static zend_ce_error: *mut ZendClassEntry;
+    pub static zend_ce_type_error: *mut ZendClassEntry;
     pub fn _zend_bailout(file: *mut c_char, line: u32);
+    pub fn zend_hash_func(str: *const c_char, len: size_t) -> zend_ulong;
+    pub fn zend_get_executed_filename() -> *const c_char;
+    pub fn zend_get_executed_lineno() -> u32;
+    /// Function pointer which is swapped by opcache
+    pub static mut zend_new_interned_string: extern "C" fn(s: *mut CZendString) -> *mut CZendString;
     pub fn zend_register_internal_class_ex(ce: *mut ZendClassEntry, parent_ce: *mut ZendClassEntry) -> *mut ZendClassEntry;
     // pemalloc
     pub fn __zend_malloc(size: size_t) -> *mut c_void;
+    pub fn zend_memory_usage(real_usage: c_int) -> size_t;
+}
+
+/// rustyphp_exception_info (see shim.c)
+#[repr(C)]
+pub struct ExceptionInfo {
+    pub object: *mut ZvalValueObject,
+    pub class_name: *mut CZendString,
+    pub message: *mut CZendString,
+    pub code: zend_long
+}
+
+// C shims (shim.c)
+extern "C" {
+    pub fn rustyphp_try_catch(cb: extern "C" fn(*mut c_void), data: *mut c_void) -> c_int;
+    pub fn rustyphp_take_exception(info: *mut ExceptionInfo) -> c_int;
+    pub fn rustyphp_has_exception() -> c_int;
+    pub fn rustyphp_function_name(ex: *mut ExecuteData) -> *mut CZendString;
+    pub fn rustyphp_find_class(name: *const c_char, len: size_t) -> *mut ZendClassEntry;
+    pub fn rustyphp_is_throwable(ce: *mut ZendClassEntry, instantiable: c_int) -> c_int;
+    pub fn rustyphp_define_class(name: *const c_char, len: size_t, parent: *mut ZendClassEntry) -> *mut ZendClassEntry;
+    pub fn rustyphp_throw_exception(ce: *mut ZendClassEntry, message: *mut CZendString, code: zend_long);
+    pub fn rustyphp_error(ty: c_int, msg: *const c_char);
+    pub fn rustyphp_emalloc_checked(size: size_t) -> *mut c_void;
+    pub fn rustyphp_erealloc_checked(ptr: *mut c_void, size: size_t) -> *mut c_void;
+    pub fn rustyphp_callable_init(callable: *mut Zval, error: *mut *mut CZendString) -> *mut c_void;
+    pub fn rustyphp_callable_call(callable: *mut c_void, retval: *mut Zval, argc: u32, argv: *mut Zval) -> c_int;
+    pub fn rustyphp_callable_free(callable: *mut c_void);
 }
 
 // TODO: debug/release definitions
-extern {
+extern "C" {
     pub fn _array_init(arg: *mut Zval, size: u32, filename: *const c_uchar, line: c_uint) -> c_int;
 }
 
-/// TOOD: Is this also vectorcall on linux?
-extern "vectorcall" {
+/// ZEND_FASTCALL: __vectorcall on Windows, the regular C calling convention on x86_64 elsewhere
+macro_rules! zend_fastcall {
+    ($($decl:tt)*) => {
+        #[cfg(windows)]
+        extern "vectorcall" { $($decl)* }
+        #[cfg(not(windows))]
+        extern "C" { $($decl)* }
+    }
+}
+
+zend_fastcall! {
     pub fn convert_to_long(op: *mut c_void);
     pub fn zend_hash_index_find(ht: *mut ZendArray, idx: zend_ulong) -> *mut Zval;
+    pub fn zend_hash_index_del(ht: *mut ZendArray, idx: zend_ulong) -> c_int;
+    pub fn zend_array_dup(source: *mut ZendArray) -> *mut ZendArray;
 }
 
  //TODO debug/release definitions
-extern "vectorcall" {
+zend_fastcall! {
     pub fn _zval_dtor_func(ptr: *mut c_void, file: *mut c_char, line: u32);
+    pub fn gc_possible_root(rc: *mut ZendRefcounted);
     pub fn _emalloc(size: size_t, filename: *const c_uchar, line: c_uint, orig_filename: *const c_uchar, orig_line: c_uint) -> *mut c_void;
     pub fn _erealloc(ptr: *mut c_void, size: size_t, filename: *const c_uchar, line: c_uint, orig_filename: *const c_uchar, orig_line: c_uint) -> *mut c_void;
     pub fn _efree(ptr: *mut c_void, filename: *const c_uchar, line: c_uint, orig_filename: *const c_uchar, orig_line: c_uint) -> *mut c_void;
     pub fn _zend_hash_index_add_new(ht: *mut ZendArray, idx: zend_ulong, data: *mut Zval, filename: *const c_uchar, line: c_uint) -> *mut Zval;
+    pub fn _zend_hash_index_update(ht: *mut ZendArray, idx: zend_ulong, data: *mut Zval, filename: *const c_uchar, line: c_uint) -> *mut Zval;
+    pub fn _zend_hash_str_update(ht: *mut ZendArray, key: *const c_char, len: size_t, data: *mut Zval, filename: *const c_uchar, line: c_uint) -> *mut Zval;
+    pub fn _zend_hash_next_index_insert(ht: *mut ZendArray, data: *mut Zval, filename: *const c_uchar, line: c_uint) -> *mut Zval;
 }
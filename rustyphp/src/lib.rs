@@ -1,5 +1,12 @@
-#![feature(placement_new_protocol, placement_in_syntax, abi_vectorcall)]
+#![cfg_attr(windows, feature(abi_vectorcall))]
+// ConversionError carries the location of a failure (argument, nested keys), it's the error of every conversion
+#![allow(clippy::result_large_err)]
 extern crate libc;
+pub extern crate inventory;
+extern crate rustyphp_plugin;
+
+/// The attributes declaring functions and classes (`use rustyphp::*` imports them)
+pub use rustyphp_plugin::{php_func, php_cls, php_methods, php_exception};
 
 pub mod php_config;
 pub use php_config::*;
@@ -17,14 +24,20 @@ pub use types::*;
 
 pub mod ffi;
 
+pub mod exception;
+pub use exception::*;
+pub mod error;
+pub use error::ErrorLevel;
+pub mod unwind;
+pub mod registry;
+pub use registry::{PhpFunction, PhpArg, PhpClass};
+
 // keep this last before testing
 pub mod zend_module;
 pub use zend_module::*;
 
 #[cfg(any(feature = "test",test))]
-pub mod testing {
-    include!(concat!(env!("OUT_DIR"), "/test_helper.rs"));
-}
+pub mod testing;
 
 #[cfg(any(feature = "test",test))]
 pub use testing::*;
@@ -1,25 +1,42 @@
 // FFI wrappers (to auto-insert debug filename/line)
+// The location shows up in Zend's leak reports, so it has to be NUL terminated
 macro_rules! zend_emalloc {
     ($size:expr) => (zend_emalloc!($size, false));
     ($size:expr, $persistent:expr) => (if $persistent { ffi::__zend_malloc($size) } else {
-        ffi::_emalloc($size, file!().as_ptr(), line!(), file!().as_ptr(), line!())
+        ffi::_emalloc($size, concat!(file!(), "\0").as_ptr(), line!(), ::std::ptr::null(), 0)
     })
 }
 
+macro_rules! zend_erealloc {
+    ($ptr:expr, $size:expr) => (ffi::_erealloc($ptr, $size, concat!(file!(), "\0").as_ptr(), line!(), ::std::ptr::null(), 0))
+}
+
 macro_rules! zend_free {
-    ($ptr:expr) => (ffi::_efree($ptr, file!().as_ptr(), line!(), file!().as_ptr(), line!()))
+    ($ptr:expr) => (ffi::_efree($ptr, concat!(file!(), "\0").as_ptr(), line!(), ::std::ptr::null(), 0))
 }
 
 macro_rules! zend_dtor {
-    ($ptr:expr) => (ffi::_zval_dtor_func($ptr, file!().as_ptr() as *mut _, line!()))
+    ($ptr:expr) => (ffi::_zval_dtor_func($ptr, concat!(file!(), "\0").as_ptr() as *mut _, line!()))
 }
 
 macro_rules! zend_array_init {
-    ($ptr:expr, $size:expr) => (ffi::_array_init($ptr, $size, file!().as_ptr() as *mut _, line!()))
+    ($ptr:expr, $size:expr) => (ffi::_array_init($ptr, $size, concat!(file!(), "\0").as_ptr() as *mut _, line!()))
 }
 
 macro_rules! zend_hash_index_add_new {
-    ($ht:expr, $key:expr, $data:expr) => (ffi::_zend_hash_index_add_new($ht, $key, $data, file!().as_ptr() as *mut _, line!()))
+    ($ht:expr, $key:expr, $data:expr) => (ffi::_zend_hash_index_add_new($ht, $key, $data, concat!(file!(), "\0").as_ptr() as *mut _, line!()))
+}
+
+macro_rules! zend_hash_index_update {
+    ($ht:expr, $key:expr, $data:expr) => (ffi::_zend_hash_index_update($ht, $key, $data, concat!(file!(), "\0").as_ptr() as *mut _, line!()))
+}
+
+macro_rules! zend_hash_str_update {
+    ($ht:expr, $key:expr, $len:expr, $data:expr) => (ffi::_zend_hash_str_update($ht, $key, $len, $data, concat!(file!(), "\0").as_ptr() as *mut _, line!()))
+}
+
+macro_rules! zend_hash_next_index_insert {
+    ($ht:expr, $data:expr) => (ffi::_zend_hash_next_index_insert($ht, $data, concat!(file!(), "\0").as_ptr() as *mut _, line!()))
 }
 
 macro_rules! convert_zval {
@@ -28,17 +45,11 @@ macro_rules! convert_zval {
     }
 }
 
+/// Register an internal class, optionally extending the class entry `$parent`
 #[macro_export]
 macro_rules! zend_define_class {
-    ($name:expr) => ({
-        let mut cls_entry: ZendClassEntry = ::std::mem::zeroed();
-        let cls_name = $name;
-        let mut name = ::rustyphp::types::zstr::CZendString::new(cls_name.len(), true);
-        name.set_value(cls_name.as_bytes());
-        cls_entry.name = Refcounted::into_raw(name);
-        // Register the class_entry and return (from the macro!) the allocated pointer to the struct
-        ffi::zend_register_internal_class_ex(&mut cls_entry, ::std::ptr::null_mut())
-    })
+    ($name:expr) => ($crate::zend_define_class!($name, ::std::ptr::null_mut()));
+    ($name:expr, $parent:expr) => ($crate::define_class($name, $parent))
 }
 
 // Exception handling wrappers
@@ -48,7 +59,7 @@ macro_rules! zend_try {
         match $expr {
             $crate::result::Result::Ok(x) => x,
             $crate::result::Result::Err(err) => {
-                throw_exception!(err);
+                $crate::throw_exception!(err);
                 return
             }
         }
@@ -61,27 +72,61 @@ macro_rules! zend_try_option {
         match $expr {
             None => {},
             Some(err) => {
-                throw_exception!(err);
+                $crate::throw_exception!(err);
                 return
             }
         }
     )
 }
 
+/// Throw a `PhpException` (or anything convertible into one, like a message `String`)
 #[macro_export]
 macro_rules! throw_exception {
     ($error:expr) => ({
-        let str_ = ::std::ffi::CString::new($error).unwrap(); //TODO: replace by match, if this fails we have a problem (endless loop)
-        unsafe { $crate::ffi::zend_throw_exception(::std::ptr::null_mut(), str_.as_ptr() as *mut _, 0) }
+        let ex: $crate::exception::PhpException = ::std::convert::From::from($error);
+        ex.throw()
     })
 }
 
+/// Raise an E_WARNING, the arguments are passed to `format!`
+#[macro_export]
+macro_rules! php_warning {
+    ($($arg:tt)*) => ($crate::error::raise($crate::error::ErrorLevel::Warning, &format!($($arg)*)))
+}
+
+/// Raise an E_NOTICE, the arguments are passed to `format!`
+#[macro_export]
+macro_rules! php_notice {
+    ($($arg:tt)*) => ($crate::error::raise($crate::error::ErrorLevel::Notice, &format!($($arg)*)))
+}
+
+/// Raise an E_DEPRECATED, the arguments are passed to `format!`
+#[macro_export]
+macro_rules! php_deprecated {
+    ($($arg:tt)*) => ($crate::error::raise($crate::error::ErrorLevel::Deprecated, &format!($($arg)*)))
+}
+
+/// Abort the request with an E_ERROR, the arguments are passed to `format!`
+///
+/// Never returns, the Rust frames up to the function wrapper are unwound (running destructors)
+/// before the error is raised, see `error::fatal` for use outside of guarded code.
+#[macro_export]
+macro_rules! php_error {
+    ($($arg:tt)*) => ($crate::error::fatal(format!($($arg)*)))
+}
+
+/// Throw an `ArgumentCountError` (`TypeError` on PHP 7.0) and return if the call passed less than `$min_args`
+/// or more than `$max_args` (an `Option`, None for variadic functions) arguments
 #[macro_export]
 macro_rules! verify_arg_count {
-    ($fn_:expr, $ex:expr, $req_args:expr) => {
-        if $ex.arg_count() < $req_args {
-            throw_exception!(format!("{}: expected {} arguments got {}", $fn_, $req_args, $ex.arg_count()));
+    ($fn_:expr, $ex:expr, $min_args:expr, $max_args:expr) => {
+        let given = $ex.arg_count();
+        if given < $min_args || $max_args.map(|max: usize| given > max).unwrap_or(false) {
+            $crate::exception::arg_count_error($fn_, given, $min_args, $max_args).throw();
             return;
         }
+    };
+    ($fn_:expr, $ex:expr, $min_args:expr) => {
+        $crate::verify_arg_count!($fn_, $ex, $min_args, None)
     }
 }
@@ -0,0 +1,214 @@
+//! Functions and classes declared by `#[php_func]`, `#[php_cls]` and `#[php_exception]`
+//!
+//! The attributes submit their definitions to `inventory`, `php_ext!` collects them when the
+//! module is loaded. Declarations can therefore live anywhere in the extension crate.
+use std::ffi::CString;
+use std::ptr;
+use inventory;
+use types::*;
+use types::execute_data::ExecuteData;
+use zend_module::{ZendFunctionEntry, ZendInternalArgInfo};
+use ffi;
+
+/// A function exported to PHP
+pub struct PhpFunction {
+    /// NUL terminated name, including the namespace ("Acme\\Geo\\distance"), the engine lowercases the lookup key
+    pub name: &'static str,
+    pub handler: extern "C" fn(*mut ExecuteData, *mut Zval),
+    pub args: &'static [PhpArg],
+    /// The leading arguments without a default value
+    pub required_args: u32,
+    /// The PHP return type declaration (see `PhpArg::type_hint`)
+    pub return_type: Option<&'static str>,
+    /// ZEND_ACC_* flags, e.g. `ffi::ZEND_ACC_DEPRECATED`
+    pub flags: u32
+}
+
+/// An argument of a `PhpFunction`
+///
+/// Default values are applied by the generated wrapper, the PHP 7 arginfo has no slot to declare them
+/// (optional arguments follow the `required_args` of the function)
+pub struct PhpArg {
+    /// NUL terminated name
+    pub name: &'static str,
+    /// Collects all remaining arguments (`...$name`), only the last argument can be variadic
+    pub variadic: bool,
+    /// Passed by reference (`&$name`)
+    pub by_ref: bool,
+    /// The PHP type declaration: "int", "?string", "array", a class name, ... (None for mixed)
+    pub type_hint: Option<&'static str>
+}
+
+/// A class registered during MINIT
+pub struct PhpClass {
+    pub name: &'static str,
+    /// The parent class, registered first if the extension declares it as well
+    pub extends: Option<&'static str>,
+    /// Register the class (and the class entries depending on it) with the engine,
+    /// fails if the parent class doesn't exist
+    pub register: unsafe fn() -> Result<(), String>
+}
+
+inventory::collect!(PhpFunction);
+inventory::collect!(PhpClass);
+
+/// _IS_BOOL and IS_CALLABLE (pseudo types only used in type declarations)
+const TYPE_HINT_BOOL: u8 = 13;
+const TYPE_HINT_CALLABLE: u8 = 14;
+
+/// Declarations the PHP 7.0 arginfo can't express (pseudo types of later versions, unions, ...),
+/// they must not end up as class names
+const UNSUPPORTED_TYPES: &[&str] = &["void", "null", "false", "true", "never", "iterable", "object", "resource", "self", "parent", "static"];
+
+/// Fails for declarations the arginfo can't express
+fn check_type(decl: &str) -> Result<(), String> {
+    let name = decl.trim_start_matches('?');
+    if name.is_empty() || name.contains(&['|', '&', '?'][..]) || UNSUPPORTED_TYPES.contains(&&*name.to_ascii_lowercase()) {
+        return Err(format!("type declaration {} is not supported", decl))
+    }
+    Ok(())
+}
+
+/// The arginfo representation of a type declaration: (type_hint, cls_name, allow_null)
+///
+/// Class names are allocated once and never freed (like the arginfo itself).
+fn type_info(decl: Option<&str>) -> Result<(u8, *const u8, bool), String> {
+    let decl = match decl {
+        Some(decl) => decl,
+        None => return Ok((0, ptr::null(), true))
+    };
+    let (nullable, name) = match decl.starts_with('?') {
+        true => (true, &decl[1..]),
+        false => (false, decl)
+    };
+    check_type(decl)?;
+    let type_hint = match &*name.to_ascii_lowercase() {
+        "mixed" => return Ok((0, ptr::null(), true)),
+        "int" => ZvalType::Long as u8,
+        "float" => ZvalType::Double as u8,
+        "string" => ZvalType::String as u8,
+        "array" => ZvalType::Array as u8,
+        "bool" => TYPE_HINT_BOOL,
+        "callable" => TYPE_HINT_CALLABLE,
+        _ => {
+            let cls_name = CString::new(name.trim_start_matches('\\')).unwrap_or_default();
+            return Ok((ZvalType::Object as u8, cls_name.into_raw() as *const u8, nullable))
+        }
+    };
+    Ok((type_hint, ptr::null(), nullable))
+}
+
+impl PhpFunction {
+    /// The zend_function_entry, the arginfo is allocated once and never freed
+    fn entry(&self) -> ZendFunctionEntry {
+        // idx=0 is the header (zend_internal_function_info) holding the required_args count and the return type
+        // unsupported declarations are left out here, `check_functions` fails MINIT for them
+        let (type_hint, cls_name, allow_null) = type_info(self.return_type).unwrap_or((0, ptr::null(), true));
+        let mut arg_info = vec![ZendInternalArgInfo {
+            arg_name: self.required_args as usize as *const _,
+            cls_name,
+            type_hint,
+            // return_reference
+            pass_by_ref: (self.flags & ffi::ZEND_ACC_RETURN_REFERENCE != 0) as u8,
+            allow_null,
+            is_variadic: false
+        }];
+        for arg in self.args {
+            let (type_hint, cls_name, allow_null) = type_info(arg.type_hint).unwrap_or((0, ptr::null(), true));
+            arg_info.push(ZendInternalArgInfo {
+                arg_name: arg.name.as_ptr(),
+                cls_name,
+                type_hint,
+                pass_by_ref: arg.by_ref as u8,
+                allow_null,
+                is_variadic: arg.variadic
+            });
+        }
+        ZendFunctionEntry {
+            name: self.name.as_ptr(),
+            handler: Some(self.handler),
+            arg_info: Box::into_raw(arg_info.into_boxed_slice()) as *mut _,
+            num_args: self.args.len() as u32,
+            flags: self.flags
+        }
+    }
+}
+
+/// Build the (null terminated) function table of the module, called once by `get_module`
+pub fn function_entries() -> *mut ZendFunctionEntry {
+    let mut entries: Vec<ZendFunctionEntry> = inventory::iter::<PhpFunction>.into_iter()
+        .map(|func| func.entry())
+        .collect();
+    entries.push(ZendFunctionEntry {
+        name: ptr::null(),
+        handler: None,
+        arg_info: ptr::null_mut(),
+        num_args: 0,
+        flags: 0
+    });
+    Box::into_raw(entries.into_boxed_slice()) as *mut _
+}
+
+/// Verify the type declarations of all functions (MINIT), the function table is built before
+/// the module is started and can't report errors itself
+pub fn check_functions() -> Result<(), String> {
+    for func in inventory::iter::<PhpFunction> {
+        let name = func.name.trim_end_matches('\0');
+        if let Err(msg) = func.return_type.map_or(Ok(()), check_type) {
+            return Err(format!("{}(): return {}", name, msg))
+        }
+        for arg in func.args {
+            if let Err(msg) = arg.type_hint.map_or(Ok(()), check_type) {
+                return Err(format!("{}(): parameter ${}: {}", name, arg.name.trim_end_matches('\0'), msg))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Class names are case insensitive, a leading backslash is optional
+fn same_class(a: &str, b: &str) -> bool {
+    a.trim_start_matches('\\').eq_ignore_ascii_case(b.trim_start_matches('\\'))
+}
+
+/// Register all classes with the engine (MINIT), parents before their subclasses
+///
+/// # Safety
+/// Only to be called once during MINIT.
+pub unsafe fn register_classes() -> Result<(), String> {
+    let mut pending: Vec<&PhpClass> = inventory::iter::<PhpClass>.into_iter().collect();
+    while !pending.is_empty() {
+        let (ready, waiting): (Vec<&PhpClass>, Vec<&PhpClass>) = pending.iter().cloned().partition(|cls| match cls.extends {
+            None => true,
+            Some(parent) => !pending.iter().any(|other| !same_class(other.name, cls.name) && same_class(other.name, parent))
+        });
+        // a cycle: register the rest anyway (failing on the first class whose parent is missing)
+        if ready.is_empty() {
+            for cls in waiting {
+                (cls.register)()?;
+            }
+            return Ok(());
+        }
+        for cls in ready {
+            (cls.register)()?;
+        }
+        pending = waiting;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_type_info() {
+    assert_eq!(type_info(None), Ok((0, ptr::null(), true)));
+    assert_eq!(type_info(Some("mixed")), Ok((0, ptr::null(), true)));
+    assert_eq!(type_info(Some("int")), Ok((ZvalType::Long as u8, ptr::null(), false)));
+    assert_eq!(type_info(Some("?String")), Ok((ZvalType::String as u8, ptr::null(), true)));
+    assert_eq!(type_info(Some("bool")), Ok((TYPE_HINT_BOOL, ptr::null(), false)));
+    let (type_hint, cls_name, allow_null) = type_info(Some("?\\Acme\\Point")).unwrap();
+    assert_eq!((type_hint, allow_null), (ZvalType::Object as u8, true));
+    assert_eq!(unsafe { CString::from_raw(cls_name as *mut _) }.to_str(), Ok("Acme\\Point"));
+    assert_eq!(type_info(Some("?iterable")), Err("type declaration ?iterable is not supported".to_owned()));
+    assert!(type_info(Some("Object")).is_err());
+    assert!(type_info(Some("void")).is_err());
+    assert!(type_info(Some("int|string")).is_err());
+}
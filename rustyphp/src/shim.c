@@ -0,0 +1,243 @@
+/*
+ * Engine functionality which is only available as C macros (setjmp based zend_try, EG())
+ * or depends on the layout of version specific structures (zend_fcall_info)
+ */
+#include "php.h"
+#include "zend_exceptions.h"
+
+typedef void (*rustyphp_callback)(void *data);
+
+typedef struct _rustyphp_exception_info {
+	zend_object *object;
+	zend_string *class_name;
+	zend_string *message;
+	zend_long code;
+} rustyphp_exception_info;
+
+/* Run cb inside zend_try, returns 1 if it bailed out (fatal error, exit, ...) */
+int rustyphp_try_catch(rustyphp_callback cb, void *data)
+{
+	int bailout = 0;
+	zend_try {
+		cb(data);
+	} zend_catch {
+		bailout = 1;
+	} zend_end_try();
+	return bailout;
+}
+
+/* Move the pending exception (EG(exception)) into info, returns 0 if there is none */
+int rustyphp_take_exception(rustyphp_exception_info *info)
+{
+	zval obj, rv, *prop;
+	zend_class_entry *base;
+
+	if (!EG(exception)) {
+		return 0;
+	}
+	info->object = EG(exception);
+	GC_REFCOUNT(info->object)++;
+	zend_clear_exception();
+
+	ZVAL_OBJ(&obj, info->object);
+	base = instanceof_function(info->object->ce, zend_ce_exception) ? zend_ce_exception : zend_ce_error;
+	info->class_name = zend_string_copy(info->object->ce->name);
+	prop = zend_read_property(base, &obj, "message", sizeof("message") - 1, 1, &rv);
+	info->message = zval_get_string(prop);
+	prop = zend_read_property(base, &obj, "code", sizeof("code") - 1, 1, &rv);
+	info->code = zval_get_long(prop);
+	return 1;
+}
+
+/* Look up a class by name without triggering the autoloader (EG(class_table) only), NULL if unknown */
+zend_class_entry *rustyphp_find_class(const char *name, size_t len)
+{
+	zend_string *lc_name;
+	zval *zv;
+
+	if (len > 0 && name[0] == '\\') {
+		name++;
+		len--;
+	}
+	lc_name = zend_string_alloc(len, 0);
+	zend_str_tolower_copy(ZSTR_VAL(lc_name), name, len);
+	zv = zend_hash_find(EG(class_table), lc_name);
+	zend_string_release(lc_name);
+	return zv ? Z_CE_P(zv) : NULL;
+}
+
+/* Whether ce implements Throwable, instantiable additionally rejects abstract classes (interfaces and traits never are) */
+int rustyphp_is_throwable(zend_class_entry *ce, int instantiable)
+{
+	if (ce->ce_flags & (ZEND_ACC_INTERFACE | ZEND_ACC_TRAIT)) {
+		return 0;
+	}
+	if (instantiable && (ce->ce_flags & (ZEND_ACC_IMPLICIT_ABSTRACT_CLASS | ZEND_ACC_EXPLICIT_ABSTRACT_CLASS))) {
+		return 0;
+	}
+	return instanceof_function(ce, zend_ce_throwable);
+}
+
+/* zend_throw_exception with a binary safe message (released here), zend_throw_exception takes a C string */
+void rustyphp_throw_exception(zend_class_entry *ce, zend_string *message, zend_long code)
+{
+	zval ex, tmp;
+	zend_class_entry *base;
+
+	/* object_init_ex can't instantiate interfaces or abstract classes, throwing a non Throwable aborts */
+	if (!ce || !rustyphp_is_throwable(ce, 1)) {
+		ce = zend_ce_exception;
+	}
+	base = instanceof_function(ce, zend_ce_exception) ? zend_ce_exception : zend_ce_error;
+
+	object_init_ex(&ex, ce);
+	ZVAL_STR(&tmp, message);
+	zend_update_property(base, &ex, "message", sizeof("message") - 1, &tmp);
+	zend_string_release(message);
+	if (code) {
+		zend_update_property_long(base, &ex, "code", sizeof("code") - 1, code);
+	}
+	zend_throw_exception_object(&ex);
+}
+
+/* The name of the function running in ex (EX(func)->common.function_name), aliases have their own. NULL if unnamed */
+zend_string *rustyphp_function_name(zend_execute_data *ex)
+{
+	return ex->func ? ex->func->common.function_name : NULL;
+}
+
+/* Whether an exception is pending (EG(exception)) */
+int rustyphp_has_exception(void)
+{
+	return EG(exception) != NULL;
+}
+
+/* Register an internal class (the entry is copied by the engine), parent may be NULL */
+zend_class_entry *rustyphp_define_class(const char *name, size_t len, zend_class_entry *parent)
+{
+	zend_class_entry ce;
+
+	INIT_CLASS_ENTRY_EX(ce, name, len, NULL);
+	return zend_register_internal_class_ex(&ce, parent);
+}
+
+/* php_error_docref (a macro for php_error_docref0 in some versions), prefixed with the active function name */
+void rustyphp_error(int type, const char *msg)
+{
+	php_error_docref(NULL, type, "%s", msg);
+}
+
+/*
+ * Whether the request heap can grow by size bytes without exceeding memory_limit
+ * (emalloc would bail out, a longjmp through the frames of the Rust allocator).
+ * Conservative: a new chunk is assumed to be needed.
+ */
+static int rustyphp_memory_available(size_t size)
+{
+	size_t limit = (size_t) PG(memory_limit);
+	size_t usage = zend_memory_usage(1);
+	size_t needed = size < ZEND_MM_CHUNK_SIZE ? ZEND_MM_CHUNK_SIZE : size + ZEND_MM_PAGE_SIZE;
+
+	return usage <= limit && limit - usage >= needed;
+}
+
+/* emalloc which returns NULL instead of bailing out when memory_limit would be exceeded */
+void *rustyphp_emalloc_checked(size_t size)
+{
+	if (!rustyphp_memory_available(size)) {
+		return NULL;
+	}
+	return emalloc(size);
+}
+
+/* erealloc which returns NULL (keeping ptr) instead of bailing out when memory_limit would be exceeded */
+void *rustyphp_erealloc_checked(void *ptr, size_t size)
+{
+	if (!rustyphp_memory_available(size)) {
+		return NULL;
+	}
+	return erealloc(ptr, size);
+}
+
+/*
+ * A resolved callable (zend_fcall_info_cache is filled once and reused for every call)
+ *
+ * __call and __callStatic are called through a trampoline function which the engine releases after
+ * each call, fcc.function_handler is stale afterwards and has to be resolved again.
+ */
+typedef struct _rustyphp_callable {
+	zend_fcall_info fci;
+	zend_fcall_info_cache fcc;
+	/* resolved to a trampoline, which is pending (not yet released by a call) */
+	zend_bool trampoline;
+	zend_bool pending;
+} rustyphp_callable;
+
+/* Resolve callable (keeping a reference to it), returns NULL and sets *error if it is not callable */
+rustyphp_callable *rustyphp_callable_init(zval *callable, zend_string **error)
+{
+	char *msg = NULL;
+	rustyphp_callable *fn = emalloc(sizeof(rustyphp_callable));
+
+	*error = NULL;
+	if (zend_fcall_info_init(callable, 0, &fn->fci, &fn->fcc, NULL, &msg) != SUCCESS) {
+		efree(fn);
+		*error = zend_string_init(msg ? msg : "unknown error", msg ? strlen(msg) : sizeof("unknown error") - 1, 0);
+		if (msg) {
+			efree(msg);
+		}
+		return NULL;
+	}
+	/* set for callables which are callable but deprecated (e.g. non-static methods called statically) */
+	if (msg) {
+		efree(msg);
+	}
+	fn->trampoline = (fn->fcc.function_handler->common.fn_flags & ZEND_ACC_CALL_VIA_TRAMPOLINE) != 0;
+	fn->pending = fn->trampoline;
+	Z_TRY_ADDREF(fn->fci.function_name);
+	return fn;
+}
+
+/* Release a trampoline which was resolved but not called (the engine does so when calling it) */
+static void rustyphp_callable_release_trampoline(rustyphp_callable *fn)
+{
+	if (fn->pending) {
+		zend_string_release(fn->fcc.function_handler->common.function_name);
+		zend_free_trampoline(fn->fcc.function_handler);
+		fn->pending = 0;
+	}
+}
+
+/* Call fn with argc arguments, returns FAILURE if the function could not be called */
+int rustyphp_callable_call(rustyphp_callable *fn, zval *retval, uint32_t argc, zval *argv)
+{
+	char *msg = NULL;
+
+	if (fn->trampoline && !fn->pending) {
+		if (!zend_is_callable_ex(&fn->fci.function_name, NULL, 0, NULL, &fn->fcc, &msg)) {
+			if (msg) {
+				efree(msg);
+			}
+			return FAILURE;
+		}
+		if (msg) {
+			efree(msg);
+		}
+		fn->fci.object = fn->fcc.object;
+		fn->pending = (fn->fcc.function_handler->common.fn_flags & ZEND_ACC_CALL_VIA_TRAMPOLINE) != 0;
+		fn->trampoline = fn->pending;
+	}
+	fn->fci.retval = retval;
+	fn->fci.params = argv;
+	fn->fci.param_count = argc;
+	/* the trampoline is released by the call */
+	fn->pending = 0;
+	return zend_call_function(&fn->fci, &fn->fcc);
+}
+
+void rustyphp_callable_free(rustyphp_callable *fn)
+{
+	rustyphp_callable_release_trampoline(fn);
+	zval_ptr_dtor(&fn->fci.function_name);
+	efree(fn);
+}
@@ -0,0 +1,131 @@
+//! Helpers for running extension tests against a PHP binary
+use std::process::Command;
+use php_config::ZEND_DEBUG;
+
+include!(concat!(env!("OUT_DIR"), "/test_helper.rs"));
+
+/// A leaked allocation as reported by the Zend memory manager (debug builds)
+#[derive(Debug, PartialEq)]
+pub struct Leak {
+    /// The allocation site (file!()/line!() passed by the allocation macros)
+    pub file: String,
+    pub line: u32,
+    pub size: usize,
+    /// How often the same leak was repeated ("Last leak repeated N times")
+    pub repeated: usize
+}
+
+/// How leaks are detected when running PHP
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LeakCheck {
+    Off,
+    /// Zend MM leak reports, requires a debug build of PHP
+    Zend,
+    /// valgrind memcheck with the Zend allocator disabled
+    Valgrind
+}
+
+impl LeakCheck {
+    /// Pick the best available leak detection, `Off` (with a notice) if there is none
+    pub fn detect() -> LeakCheck {
+        if ZEND_DEBUG == 1 {
+            return LeakCheck::Zend
+        }
+        match Command::new("valgrind").arg("--version").output() {
+            Ok(ref out) if out.status.success() => LeakCheck::Valgrind,
+            _ => {
+                println!("leak_check skipped: requires a debug build of PHP or valgrind");
+                LeakCheck::Off
+            }
+        }
+    }
+}
+
+/// Build the command running the PHP binary (wrapped into valgrind if requested)
+pub fn php_command(leak_check: LeakCheck) -> Command {
+    match leak_check {
+        LeakCheck::Off => Command::new(PHP_PATH),
+        LeakCheck::Zend => {
+            let mut cmd = Command::new(PHP_PATH);
+            cmd.env("USE_ZEND_ALLOC", "1").arg("-dreport_memleaks=1");
+            // AddressSanitizer builds report leaks on their own
+            cmd.env("ASAN_OPTIONS", "detect_leaks=1");
+            cmd
+        },
+        LeakCheck::Valgrind => {
+            let mut cmd = Command::new("valgrind");
+            cmd.env("USE_ZEND_ALLOC", "0")
+                .env("ZEND_DONT_UNLOAD_MODULES", "1")
+                .args(["-q", "--leak-check=full", "--errors-for-leak-kinds=definite", PHP_PATH]);
+            cmd
+        }
+    }
+}
+
+/// Parse the leak reports the Zend memory manager prints to stderr on request shutdown:
+/// `file.rs(42) :  Freeing 0x7F2A1C0E6000 (32 bytes), script=Standard input code`
+pub fn parse_zend_leaks(stderr: &str) -> Vec<Leak> {
+    let mut leaks: Vec<Leak> = vec![];
+    for line in stderr.lines() {
+        let line = line.trim();
+        if let Some(repeated) = line.strip_prefix("Last leak repeated ") {
+            let count = repeated.split(' ').next().and_then(|x| x.parse::<usize>().ok());
+            if let (Some(count), Some(last)) = (count, leaks.last_mut()) {
+                last.repeated += count;
+            }
+            continue;
+        }
+        let sep = match line.find(") :  Freeing ") {
+            Some(sep) => sep,
+            None => continue
+        };
+        let (site, rest) = line.split_at(sep);
+        let open = match site.rfind('(') {
+            Some(open) => open,
+            None => continue
+        };
+        let size = rest.find(" (").and_then(|start| {
+            let rest = &rest[start + 2..];
+            rest.find(" bytes").and_then(|end| rest[..end].parse().ok())
+        });
+        leaks.push(Leak {
+            file: site[..open].to_owned(),
+            line: site[open + 1..].parse().unwrap_or(0),
+            size: size.unwrap_or(0),
+            repeated: 0
+        });
+    }
+    leaks
+}
+
+/// Fail with the offending allocation sites if the PHP run leaked memory
+pub fn assert_no_leaks(leak_check: LeakCheck, stderr: &str) {
+    match leak_check {
+        LeakCheck::Off => {},
+        LeakCheck::Zend => {
+            let leaks = parse_zend_leaks(stderr);
+            let sites: Vec<String> = leaks.iter()
+                .map(|leak| format!("  {}:{} ({} bytes, repeated {} times)", leak.file, leak.line, leak.size, leak.repeated))
+                .collect();
+            assert!(leaks.is_empty(), "memory leaks detected:\n{}", sites.join("\n"));
+            assert!(!stderr.contains("LeakSanitizer"), "memory leaks detected:\n{}", stderr);
+        },
+        LeakCheck::Valgrind => {
+            assert!(!stderr.contains("definitely lost in"), "memory leaks detected:\n{}", stderr);
+        }
+    }
+}
+
+#[test]
+fn test_parse_zend_leaks() {
+    let stderr = "[Sat Jun 11 12:00:00 2016]  Script:  'Standard input code'\n\
+        src/types/zstr.rs(41) :  Freeing 0x7F2A1C0E6000 (32 bytes), script=Standard input code\n\
+        Last leak repeated 99 times\n\
+        src/types/array.rs(7) :  Freeing 0x7F2A1C0E6100 (56 bytes), script=Standard input code\n\
+        === Total 101 memory leaks detected ===\n";
+    assert_eq!(parse_zend_leaks(stderr), vec![
+        Leak { file: "src/types/zstr.rs".to_owned(), line: 41, size: 32, repeated: 99 },
+        Leak { file: "src/types/array.rs".to_owned(), line: 7, size: 56, repeated: 0 }
+    ]);
+    assert_eq!(parse_zend_leaks("bool(true)\n"), vec![]);
+}
@@ -0,0 +1,116 @@
+//! Special parameter types of exported functions
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::vec;
+use zend_mm::ZendRefcounted;
+use types::*;
+
+/// Collects all remaining arguments of a call (`function f(...$values)`),
+/// has to be the last parameter
+///
+/// ```ignore
+/// #[php_func]
+/// fn my_sum(nums: Variadic<i64>) -> i64 {
+///     nums.iter().sum()
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variadic<T>(pub Vec<T>);
+
+impl<T> Variadic<T> {
+    #[inline]
+    pub fn into_inner(self) -> Vec<T> {
+        self.0
+    }
+}
+
+impl<T> Deref for Variadic<T> {
+    type Target = Vec<T>;
+
+    fn deref(&self) -> &Vec<T> {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Variadic<T> {
+    fn deref_mut(&mut self) -> &mut Vec<T> {
+        &mut self.0
+    }
+}
+
+impl<T> IntoIterator for Variadic<T> {
+    type Item = T;
+    type IntoIter = vec::IntoIter<T>;
+
+    fn into_iter(self) -> vec::IntoIter<T> {
+        self.0.into_iter()
+    }
+}
+
+impl<'a, T> IntoIterator for &'a Variadic<T> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+
+    fn into_iter(self) -> slice::Iter<'a, T> {
+        self.0.iter()
+    }
+}
+
+/// zend_reference
+#[repr(C)]
+pub struct ZendReference {
+    pub gc: ZendRefcounted,
+    pub val: Zval
+}
+
+/// A parameter passed by reference (`function f(&$out)`), assignments are visible to the caller
+///
+/// ```ignore
+/// #[php_func]
+/// fn my_parse(input: String, mut out: ByRef<i64>) -> bool {
+///     match input.parse() {
+///         Ok(value) => { out.set(value); true },
+///         Err(_) => false
+///     }
+/// }
+/// ```
+pub struct ByRef<'a, T> {
+    /// The referenced value (inside the zend_reference)
+    zv: &'a mut Zval,
+    marker: PhantomData<T>
+}
+
+impl<'a, T> ByRef<'a, T> {
+    /// Wrap a reference zval (IS_REFERENCE)
+    pub fn new(zv: &'a mut Zval) -> Result<ByRef<'a, T>, ConversionError> {
+        if zv.type_() != ZvalType::Reference as u32 {
+            return Err(ConversionError::type_mismatch("reference", zv.type_name()))
+        }
+        let reference = unsafe { &mut *(zv.value.as_ptr_mut().data as *mut ZendReference) };
+        Ok(ByRef { zv: &mut reference.val, marker: PhantomData })
+    }
+
+    /// The current value of the caller's variable
+    #[inline]
+    pub fn zval(&mut self) -> &mut Zval {
+        self.zv
+    }
+
+    /// Convert the current value of the caller's variable
+    pub fn get(&mut self) -> Result<T, ConversionError> where for<'b> Result<T, ConversionError>: From<&'b mut Zval> {
+        From::from(&mut *self.zv)
+    }
+
+    /// Assign a new value to the caller's variable, the previous value is released
+    pub fn set(&mut self, value: T) -> Option<ConversionError> where T: AssignTo {
+        let mut tmp = ZvalGuard(Zval::new());
+        if let Some(err) = value.assign_to(&mut tmp) {
+            return Some(err)
+        }
+        // the guard now holds (and releases) the previous value
+        mem::swap(self.zv, &mut tmp.0);
+        None
+    }
+}
@@ -1,11 +1,18 @@
 //! zend_array/Hastable related stuff
+use std::fmt;
 use std::mem;
+use std::slice;
 use php_config::*;
 use types::*;
 use ffi;
-use zend_mm::{Refcounted, ZendRefcounted};
+use exception::{PhpException, ExceptionClass};
+use zend_mm::{Refcounted, RefcountedType, ZendRefcounted};
 use zstr::CZendString;
 
+/// GC_FLAGS of zend_array (zend_refcounted.u.v.flags)
+static IS_ARRAY_IMMUTABLE: u32 = 1<<1;
+static GC_FLAGS_SHIFT: u32 = 8;
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct ZendArray {
@@ -19,34 +26,244 @@ pub struct ZendArray {
     table_size: u32,
     inter_ptr: u32,
     next_free_el: zend_long,
-    dtor_func: extern "C" fn(*mut Zval)
+    dtor_func: Option<extern "C" fn(*mut Zval)>
 }
 
+unsafe impl RefcountedType for ZendArray {}
+
 impl ZendArray {
     /// Initialize the returned array after by either passing it into zend_hash_init
     /// or by passing the zval into _array_init
     pub fn new() -> Refcounted<ZendArray> {
-        let arr: ZendArray = unsafe { mem::uninitialized() };
+        let arr: ZendArray = unsafe { mem::zeroed() };
         Refcounted::new(arr)
     }
+
+    /// The number of elements stored in the array
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.num_elems as usize
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.num_elems == 0
+    }
+
+    /// Immutable arrays live in opcache shared memory (or are compile time literals)
+    /// and must never be written to or have their refcount touched
+    #[inline]
+    pub fn is_immutable(&self) -> bool {
+        ((self.refc.type_info >> GC_FLAGS_SHIFT) & IS_ARRAY_IMMUTABLE) == IS_ARRAY_IMMUTABLE
+    }
+
+    /// Whether other zvals still point to this array (writes would be visible to them)
+    #[inline]
+    pub fn is_shared(&self) -> bool {
+        self.is_immutable() || self.refc.refcount > 1
+    }
+
+    /// Create a private copy of the array (zend_array_dup), which can be mutated freely
+    pub fn to_owned(&self) -> Refcounted<ZendArray> {
+        let ptr = unsafe { ffi::zend_array_dup(self as *const _ as *mut _) };
+        Refcounted::from_raw(ptr)
+    }
+
+    /// Insert or replace the value stored at `idx`
+    pub fn insert<T: AssignTo>(&mut self, idx: zend_ulong, value: T) -> Option<PhpException> {
+        if let Some(err) = self.verify_writable() {
+            return Some(err)
+        }
+        let mut tmp = Zval::new();
+        if let Some(err) = value.assign_to(&mut tmp) {
+            return Some(err.into())
+        }
+        // the hashtable takes over the reference held by tmp
+        unsafe { zend_hash_index_update!(self, idx, &mut tmp); }
+        None
+    }
+
+    /// Append a value using the next free integer key
+    pub fn push<T: AssignTo>(&mut self, value: T) -> Option<PhpException> {
+        if let Some(err) = self.verify_writable() {
+            return Some(err)
+        }
+        let mut tmp = Zval::new();
+        if let Some(err) = value.assign_to(&mut tmp) {
+            return Some(err.into())
+        }
+        let ret = unsafe { zend_hash_next_index_insert!(self, &mut tmp) };
+        if ret.is_null() {
+            // the value wasn't consumed by the hashtable, so release it again
+            mem::drop(ZvalGuard(tmp));
+            return Some(PhpException::new("Cannot add element to the array as the next element is already occupied").class(ExceptionClass::Error))
+        }
+        None
+    }
+
+    /// Insert or replace the value stored at the string `key`,
+    /// numeric strings are stored as integer keys like PHP does ("42" -> 42)
+    pub fn insert_str<T: AssignTo>(&mut self, key: &str, value: T) -> Option<PhpException> {
+        if let Some(idx) = numeric_key(key) {
+            return self.insert(idx, value)
+        }
+        if let Some(err) = self.verify_writable() {
+            return Some(err)
+        }
+        let mut tmp = Zval::new();
+        if let Some(err) = value.assign_to(&mut tmp) {
+            return Some(err.into())
+        }
+        unsafe { zend_hash_str_update!(self, key.as_ptr() as *const _, key.len(), &mut tmp); }
+        None
+    }
+
+    /// Remove the value stored at `idx`, returns whether an element was removed
+    pub fn remove(&mut self, idx: zend_ulong) -> Result<bool, PhpException> {
+        if let Some(err) = self.verify_writable() {
+            return Err(err)
+        }
+        Ok(unsafe { ffi::zend_hash_index_del(self, idx) } == 0)
+    }
+
+    /// Mutations through a shared array would leak into other variables,
+    /// `&mut ZendArray` is only handed out after separation so this is a last line of defense
+    #[inline]
+    fn verify_writable(&self) -> Option<PhpException> {
+        if self.is_shared() {
+            return Some(PhpException::new("ZendArray: cannot modify a shared array (separate it or use to_owned)").class(ExceptionClass::Error))
+        }
+        None
+    }
 }
 
 impl<'a> ZendArray {
-    pub fn get<T>(&self, idx: zend_ulong) -> Result<T, String> where Result<T, String>: From<&'a mut Zval> {
+    /// Convert the value at `idx`, the array is only read (see `iter_mut` for mutable access)
+    pub fn get<T>(&'a self, idx: zend_ulong) -> Result<T, ConversionError> where Result<T, ConversionError>: From<&'a Zval> {
         let zv_ptr = unsafe { ffi::zend_hash_index_find(self as *const _ as *mut _, idx) };
         if zv_ptr.is_null() {
-            return Err(format!("No value for given index of {}", idx))
+            return Err(ConversionError::custom(format!("No value for given index of {}", idx)))
         }
-        let zv: &mut Zval = unsafe { mem::transmute(zv_ptr) };
-        // maybe we have to clone the zval here if it's reused by the caller..
-        From::from(zv)
+        let zv: &'a Zval = unsafe { &*zv_ptr };
+        let ret: Result<T, ConversionError> = From::from(zv);
+        ret.map_err(|err| err.in_key(idx))
+    }
+
+    /// Iterate over all elements in insertion order
+    pub fn iter(&'a self) -> Iter<'a> {
+        Iter { arr: self, pos: 0 }
+    }
+
+    /// Iterate over all elements in insertion order, with mutable access to the values
+    ///
+    /// `&mut ZendArray` is only handed out after separating the array (see `separate_array`),
+    /// so the values are not visible through other variables.
+    pub fn iter_mut(&'a mut self) -> IterMut<'a> {
+        assert!(!self.is_shared(), "ZendArray::iter_mut: the array has to be separated first");
+        IterMut { arr: self, pos: 0 }
+    }
+
+    /// The next used bucket at or after `*pos`
+    fn next_bucket(&self, pos: &mut u32) -> Option<*mut ZendBucket> {
+        while *pos < self.num_used {
+            let bucket = unsafe { self.ar_data.offset(*pos as isize) };
+            *pos += 1;
+            // deleted elements are kept as IS_UNDEF until the hashtable is rehashed
+            if unsafe { (*bucket).val.type_() } != 0 {
+                return Some(bucket)
+            }
+        }
+        None
+    }
+}
+
+/// The key of an array element
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArrayKey<'a> {
+    Index(zend_ulong),
+    Str(&'a [u8])
+}
+
+impl<'a> fmt::Display for ArrayKey<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ArrayKey::Index(idx) => write!(f, "{}", idx),
+            ArrayKey::Str(key) => write!(f, "\"{}\"", String::from_utf8_lossy(key))
+        }
+    }
+}
+
+pub struct Iter<'a> {
+    arr: &'a ZendArray,
+    pos: u32
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (ArrayKey<'a>, &'a Zval);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let bucket: &'a ZendBucket = unsafe { &*self.arr.next_bucket(&mut self.pos)? };
+        Some((bucket.key(), &bucket.val))
     }
 }
 
+pub struct IterMut<'a> {
+    arr: &'a mut ZendArray,
+    pos: u32
+}
+
+impl<'a> Iterator for IterMut<'a> {
+    type Item = (ArrayKey<'a>, &'a mut Zval);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // every bucket is visited once, so the returned references never alias
+        let bucket: &'a mut ZendBucket = unsafe { &mut *self.arr.next_bucket(&mut self.pos)? };
+        Some((bucket.key(), &mut bucket.val))
+    }
+}
+
+/// ZEND_HANDLE_NUMERIC_STR: canonical integer strings are integer keys
+pub fn numeric_key(key: &str) -> Option<zend_ulong> {
+    match key.parse::<zend_long>() {
+        Ok(idx) if idx.to_string() == key => Some(idx as zend_ulong),
+        _ => None
+    }
+}
+
+/// SEPARATE_ARRAY: Ensure the array stored in `zv` is only referenced by `zv`
+/// so it can be modified without affecting other variables
+///
+/// # Safety
+/// `zv` has to hold an array.
+pub unsafe fn separate_array(zv: &mut Zval) {
+    let arr = &mut *(zv.value.as_ptr_mut().data as *mut ZendArray);
+    if !arr.is_shared() {
+        return;
+    }
+    let dup = ffi::zend_array_dup(arr);
+    // release our reference to the shared array (a no-op for immutable arrays)
+    mem::drop(Refcounted::from_raw(arr as *mut ZendArray));
+    zv.value.as_ptr_mut().data = dup as *mut _;
+    zv.set_type(ZvalType::Array);
+}
+
 #[derive(Debug)]
 #[repr(C)]
 struct ZendBucket {
     val: Zval,
     h: zend_ulong,
-    key: CZendString
+    /// null for integer keys
+    key: *mut CZendString
+}
+
+impl ZendBucket {
+    fn key<'a>(&self) -> ArrayKey<'a> {
+        if self.key.is_null() {
+            return ArrayKey::Index(self.h)
+        }
+        ArrayKey::Str(unsafe {
+            let zs = &*self.key;
+            slice::from_raw_parts(zs.value.as_ptr(), zs.header.len)
+        })
+    }
 }
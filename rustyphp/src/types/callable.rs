@@ -0,0 +1,88 @@
+//! PHP callables, called from Rust
+use std::fmt;
+use types::*;
+use types::zstr::ZendStr;
+use exception::{self, PhpException, ExceptionClass};
+use ffi;
+
+/// A resolved PHP callable: a closure, "func", "Class::method", [$obj, 'method'], an invokable object, ...
+///
+/// The function is looked up once (`zend_is_callable_ex`), so repeated calls are cheap
+/// (except for `__call`/`__callStatic`, the engine releases their trampoline after every call).
+/// A callable is only valid during the request it was created in.
+///
+/// ```ignore
+/// #[php_func]
+/// fn my_each(values: Vec<i64>, func: Callable) {
+///     for value in values {
+///         // an exception thrown by func is rethrown
+///         zend_try!(func.call(&[&value]));
+///     }
+/// }
+/// ```
+pub struct Callable {
+    /// rustyphp_callable (see shim.c)
+    ptr: *mut c_void
+}
+
+impl Callable {
+    /// Resolve a callable (`is_callable()`)
+    pub fn new(zv: &mut Zval) -> Result<Callable, ConversionError> {
+        let mut error = ::std::ptr::null_mut();
+        let ptr = unsafe { ffi::rustyphp_callable_init(zv, &mut error) };
+        if !ptr.is_null() {
+            return Ok(Callable { ptr })
+        }
+        let error = unsafe { ZendStr::from_raw(error) };
+        Err(ConversionError::new(ConversionErrorKind::Callback(String::from_utf8_lossy(error.as_bytes()).into_owned())))
+    }
+
+    /// Call with Rust values as arguments
+    pub fn call(&self, args: &[&dyn AssignTo]) -> Result<OwnedZval, PhpException> {
+        let mut params = Vec::with_capacity(args.len());
+        for arg in args {
+            let mut param = Zval::new();
+            let err = arg.assign_to(&mut param);
+            params.push(param);
+            if let Some(err) = err {
+                release(params);
+                return Err(err.into())
+            }
+        }
+        let ret = self.call_with(&mut params);
+        release(params);
+        ret
+    }
+
+    /// Call with zvals as arguments (e.g. forwarded from `CallContext::arg`), the caller keeps ownership
+    pub fn call_with(&self, args: &mut [Zval]) -> Result<OwnedZval, PhpException> {
+        let mut ret = ZvalGuard(Zval::new());
+        let status = exception::try_catch(|| unsafe {
+            ffi::rustyphp_callable_call(self.ptr, &mut ret.0, args.len() as u32, args.as_mut_ptr())
+        })?;
+        // FAILURE (-1)
+        if status != 0 {
+            return Err(PhpException::new("Could not call the callable").class(ExceptionClass::Error))
+        }
+        Ok(ret)
+    }
+}
+
+/// Release the argument values created by `call`
+fn release(params: Vec<Zval>) {
+    for param in params {
+        drop(ZvalGuard(param));
+    }
+}
+
+impl Drop for Callable {
+    fn drop(&mut self) {
+        unsafe { ffi::rustyphp_callable_free(self.ptr) };
+    }
+}
+
+impl fmt::Debug for Callable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Callable({:?})", self.ptr)
+    }
+}
@@ -1,7 +1,10 @@
-use std::mem;
+use std::ffi::CStr;
+use std::marker::PhantomData;
 
 use super::*;
 use ::php_config;
+use ::ffi;
+use ::zend_module::ZendClassEntry;
 
 //
 #[derive(Debug)]
@@ -13,7 +16,7 @@ pub struct ExecuteData
     return_value: *mut c_void,
     func: *mut c_void,
     pub this: Zval,
-    called_scope: *mut c_void,
+    called_scope: *mut ZendClassEntry,
     pub prev_execute_data: *mut c_void
 }
 
@@ -27,8 +30,109 @@ impl ExecuteData {
     /// Fetch an PHP argument from current_execute_data (first arg is idx = 0)
     #[inline]
     pub fn arg(&mut self, idx: usize) -> &mut Zval {
+        unsafe { &mut *ExecuteData::arg_ptr(self, idx) }
+    }
+
+    /// The slot of argument `idx`, the arguments follow the frame (ZEND_CALL_ARG)
+    ///
+    /// Derived from the raw frame pointer, so distinct slots can be borrowed at the same time.
+    ///
+    /// # Safety
+    /// `ex` has to be a valid call frame, `idx` is not checked against the argument count.
+    #[inline]
+    pub unsafe fn arg_ptr(ex: *mut ExecuteData, idx: usize) -> *mut Zval {
+        (ex as *mut Zval).offset(php_config::ZEND_CALL_FRAME_SLOT as isize + idx as isize)
+    }
+
+    /// The class the function was called on (`static::class`), None outside of class scope
+    #[inline]
+    pub fn called_scope(&self) -> Option<&ZendClassEntry> {
+        unsafe { self.called_scope.as_ref() }
+    }
+
+    /// The name the running function was called by, aliases sharing a handler each have their own
+    pub fn function_name(&self) -> Option<String> {
+        unsafe {
+            let name = ffi::rustyphp_function_name(self as *const _ as *mut _);
+            name.as_ref().map(|name| String::from_utf8_lossy(name.as_bytes()).into_owned())
+        }
+    }
+}
+
+/// The call of an exported function, for functions needing more than their typed arguments
+///
+/// Declare a `&mut CallContext` parameter (any position, it is not a PHP argument):
+///
+/// ```ignore
+/// #[php_func]
+/// fn my_count_args(ctx: &mut CallContext) {
+///     let count = ctx.arg_count() as i64;
+///     zend_try_option!(count.assign_to(ctx.return_value()));
+/// }
+/// ```
+///
+/// The parameters of the function may still borrow their argument slots (`&mut Zval`, `ByRef<T>`, ...),
+/// those slots are not handed out again.
+pub struct CallContext<'a> {
+    ex: *mut ExecuteData,
+    ret: *mut Zval,
+    /// Whether the argument slot is borrowed by a parameter
+    borrowed: fn(usize) -> bool,
+    marker: PhantomData<&'a mut ExecuteData>
+}
+
+impl<'a> CallContext<'a> {
+    /// # Safety
+    /// `ex` and `ret` have to be valid for `'a` and `borrowed` has to cover every argument slot
+    /// borrowed elsewhere during that time
+    #[inline]
+    pub unsafe fn new(ex: *mut ExecuteData, ret: *mut Zval, borrowed: fn(usize) -> bool) -> CallContext<'a> {
+        CallContext { ex, ret, borrowed, marker: PhantomData }
+    }
+
+    /// The raw call frame, the argument slots borrowed by parameters must not be accessed
+    #[inline]
+    pub fn execute_data(&self) -> *mut ExecuteData {
+        self.ex
+    }
+
+    /// The return value, a value returned by the Rust function is assigned afterwards (overwriting it)
+    #[inline]
+    pub fn return_value(&mut self) -> &mut Zval {
+        unsafe { &mut *self.ret }
+    }
+
+    /// The number of passed arguments (`func_num_args()`)
+    #[inline]
+    pub fn arg_count(&self) -> usize {
+        unsafe { (*self.ex).arg_count() }
+    }
+
+    /// The argument `idx` (first arg is idx = 0) as passed, including those beyond the declared parameters,
+    /// None if it has not been passed or a parameter borrows it
+    pub fn arg(&mut self, idx: usize) -> Option<&mut Zval> {
+        match idx < self.arg_count() && !(self.borrowed)(idx) {
+            true => Some(unsafe { &mut *ExecuteData::arg_ptr(self.ex, idx) }),
+            false => None
+        }
+    }
+
+    /// Convert the argument `idx` (`func_get_arg()`), None if it has not been passed
+    pub fn get_arg<T>(&mut self, idx: usize) -> Option<Result<T, ConversionError>> where for<'b> Result<T, ConversionError>: From<&'b mut Zval> {
+        self.arg(idx).map(From::from)
+    }
+
+    /// The class the function was called on, see `ExecuteData::called_scope`
+    #[inline]
+    pub fn called_scope(&self) -> Option<&ZendClassEntry> {
+        unsafe { (*self.ex).called_scope() }
+    }
+
+    /// The file and line of the calling PHP code
+    pub fn caller(&self) -> (String, u32) {
         unsafe {
-            mem::transmute((self as *mut _ as *mut Zval).offset(php_config::ZEND_CALL_FRAME_SLOT as isize + idx as isize))
+            let file = CStr::from_ptr(ffi::zend_get_executed_filename()).to_string_lossy().into_owned();
+            (file, ffi::zend_get_executed_lineno())
         }
     }
 }
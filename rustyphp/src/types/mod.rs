@@ -1,4 +1,4 @@
-/// Wrappers for libc types
+//! Wrappers for libc types
 
 #[allow(non_camel_case_types)]
 pub type c_void = ::libc::c_void;
@@ -14,10 +14,15 @@ pub type size_t = ::libc::size_t;
 pub type c_ushort = ::libc::c_ushort;
 
 pub mod execute_data;
+pub use self::execute_data::CallContext;
 pub mod zstr;
 pub mod zval;
 pub mod array;
-pub use self::array::ZendArray;
+pub mod args;
+pub use self::args::{Variadic, ByRef};
+pub mod callable;
+pub use self::callable::Callable;
+pub use self::array::{ZendArray, ArrayKey};
 pub use self::zval::*;
 
 pub mod ops;
@@ -2,12 +2,12 @@
 //! value -> zval
 
 //TODO: call dtor on cases where already a value is stored in the zval
+use std::collections::HashMap;
 use std::mem;
 use php_config::*;
 use types::*;
 use ffi;
-use zstr::CZendString;
-use zend_mm::Refcounted;
+use zstr::ZendStr;
 
 macro_rules! primitive_assign_help {
     ($target:expr, long, $_self:expr, $value_ty:ty) => ($target.value.data = *$_self as $value_ty);
@@ -24,7 +24,7 @@ macro_rules! primitive_assign {
             $(
                 impl AssignTo for $from_ty {
                     #[inline]
-                    fn assign_to(&self, target: &mut Zval) -> Option<String> {
+                    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
                         target.set_type($target_zvt);
                         primitive_assign_help!(target, $conv_ty, self, $value_ty);
                         None
@@ -35,12 +35,12 @@ macro_rules! primitive_assign {
     }
 }
 pub trait AssignTo  {
-    fn assign_to(&self, target: &mut Zval) -> Option<String>;
+    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError>;
 }
 
 impl AssignTo for bool {
     #[inline]
-    fn assign_to(&self, target: &mut Zval) -> Option<String> {
+    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
         target.set_type(match *self {
             true => ZvalType::True,
             false => ZvalType::False
@@ -54,7 +54,7 @@ primitive_assign!(f64, f32 => ZvalType::Double, zend_double : as_double_mut);
 
 impl AssignTo for ZvalValueObject {
     #[inline]
-    fn assign_to(&self, target: &mut Zval) -> Option<String> {
+    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
         target.set_type(ZvalType::Object);
         let ptr = self as *const _ as *mut _;
         unsafe { target.value.as_ptr_mut().data = ptr };
@@ -64,10 +64,10 @@ impl AssignTo for ZvalValueObject {
 
 impl<T: AssignTo> AssignTo for Option<T> {
     #[inline]
-    fn assign_to(&self, target: &mut Zval) -> Option<String> {
+    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
         match *self {
             None => target.set_type(ZvalType::Null),
-            Some(ref val) => { val.assign_to(target); }
+            Some(ref val) => return val.assign_to(target)
         };
         None
     }
@@ -75,39 +75,111 @@ impl<T: AssignTo> AssignTo for Option<T> {
 
 impl AssignTo for String {
     #[inline]
-    fn assign_to(&self, target: &mut Zval) -> Option<String> {
+    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
         (self as &str).assign_to(target)
     }
 }
 
-impl<'a> AssignTo for &'a str {
-    fn assign_to(&self, target: &mut Zval) -> Option<String> {
-        let pzv: &mut ZvalValuePtr = unsafe { mem::transmute(&mut target.value) };
+/// Move the string reference into the zval
+#[inline]
+fn assign_zstr(zstr: ZendStr, target: &mut Zval) {
+    // interned strings are stored as IS_STRING without the refcounted type flags
+    let interned = zstr.is_interned();
+    let pzv: &mut ZvalValuePtr = unsafe { mem::transmute(&mut target.value) };
+    pzv.data = ZendStr::into_raw(zstr) as *mut _;
+    target.set_type(ZvalType::String);
+    if interned {
+        target.u1 = ZvalType::String as u32;
+    }
+}
 
-        let mut zstr = CZendString::new(self.len(), false);
-        zstr.set_value(self.as_bytes());
+impl AssignTo for &str {
+    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
+        assign_zstr(ZendStr::from_str(self), target);
+        None
+    }
+}
 
-        pzv.data = Refcounted::into_raw(zstr) as *mut _;
-        target.set_type(ZvalType::String);
+impl AssignTo for ZendStr {
+    #[inline]
+    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
+        assign_zstr(self.clone(), target);
         None
     }
 }
 
-impl<T: AssignTo> AssignTo for Vec<T> {
-    fn assign_to(&self, target: &mut Zval) -> Option<String> {
-        let new_arr = ZendArray::new();
-        let pzv: &mut ZvalValuePtr = unsafe { mem::transmute(&mut target.value) };
+/// Initialize `target` as an empty array with space for `size` elements
+#[inline]
+fn init_array(target: &mut Zval, size: usize) -> *mut ZendArray {
+    // _array_init allocates the hashtable itself
+    unsafe {
+        zend_array_init!(target, size as u32);
+        target.value.as_ptr().data as *mut _
+    }
+}
+
+/// Append `value` to a freshly initialized list at position `k`
+#[inline]
+fn add_list_elem<T: AssignTo>(ht_ptr: *mut ZendArray, k: usize, value: &T) -> Option<ConversionError> {
+    let mut tmp = Zval::new();
+    if let Some(err) = value.assign_to(&mut tmp) {
+        return Some(err.in_key(k))
+    }
+    unsafe { zend_hash_index_add_new!(ht_ptr, k as zend_ulong, &mut tmp); }
+    None
+}
 
-        pzv.data = Refcounted::into_raw(new_arr) as *mut _;
-        target.set_type(ZvalType::Array);
-        unsafe { zend_array_init!(target, self.len() as u32) };
+impl<T: AssignTo> AssignTo for Vec<T> {
+    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
+        let ht_ptr = init_array(target, self.len());
         // copy the vector into the array...
-        let ht_ptr = pzv.data;
-        let mut tmp = Zval::new();
         for (k, v) in self.iter().enumerate() {
-            v.assign_to(&mut tmp);
-            unsafe { zend_hash_index_add_new!(ht_ptr as *mut _, k as zend_ulong, &mut tmp); }
+            if let Some(err) = add_list_elem(ht_ptr, k, v) {
+                return Some(err)
+            }
+        }
+        None
+    }
+}
+
+impl<T: AssignTo> AssignTo for HashMap<String, T> {
+    fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
+        let ht_ptr = init_array(target, self.len());
+        for (k, v) in self.iter() {
+            let mut tmp = Zval::new();
+            if let Some(err) = v.assign_to(&mut tmp) {
+                return Some(err.in_key(format!("\"{}\"", k)))
+            }
+            unsafe {
+                match array::numeric_key(k) {
+                    Some(idx) => zend_hash_index_update!(ht_ptr, idx, &mut tmp),
+                    None => zend_hash_str_update!(ht_ptr, k.as_ptr() as *const _, k.len(), &mut tmp)
+                };
+            }
         }
         None
     }
-}
\ No newline at end of file
+}
+
+/// Tuples are returned as lists
+macro_rules! tuple_assign {
+    ($len:expr => $($idx:tt $name:ident),+) => {
+        impl<$($name: AssignTo),+> AssignTo for ($($name,)+) {
+            fn assign_to(&self, target: &mut Zval) -> Option<ConversionError> {
+                let ht_ptr = init_array(target, $len);
+                $(
+                    if let Some(err) = add_list_elem(ht_ptr, $idx, &self.$idx) {
+                        return Some(err)
+                    }
+                )+
+                None
+            }
+        }
+    }
+}
+tuple_assign!(1 => 0 A);
+tuple_assign!(2 => 0 A, 1 B);
+tuple_assign!(3 => 0 A, 1 B, 2 C);
+tuple_assign!(4 => 0 A, 1 B, 2 C, 3 D);
+tuple_assign!(5 => 0 A, 1 B, 2 C, 3 D, 4 E);
+tuple_assign!(6 => 0 A, 1 B, 2 C, 3 D, 4 E, 5 F);
\ No newline at end of file
@@ -1,8 +1,7 @@
-/// (dynamic type) Conversion functions (zval[any] -> T)
-/// Convert the data type if it is not matching
+//! (dynamic type) Conversion functions (zval[any] -> T)
+//! Convert the data type if it is not matching
 
 use std::ops::{Deref};
-use php_config::*;
 use types::*;
 use ffi;
 
@@ -16,9 +15,10 @@ impl<T> Deref for ConvertZvalAs<T> {
     }
 }
 
-impl <'a> From<&'a mut Zval> for Result<ConvertZvalAs<u16>, String> {
-    fn from(zv: &mut Zval) -> Result<ConvertZvalAs<u16>, String> {
+impl From<&mut Zval> for Result<ConvertZvalAs<u16>, ConversionError> {
+    fn from(zv: &mut Zval) -> Result<ConvertZvalAs<u16>, ConversionError> {
         convert_zval!(convert_to_long, zv);
-        Ok(ConvertZvalAs(try!(From::from(zv))))
+        let value: Result<u16, ConversionError> = From::from(zv);
+        Ok(ConvertZvalAs(value?))
     }
 }
@@ -0,0 +1,182 @@
+//! Structured errors of the conversion layer (zval <-> Rust values)
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::str;
+
+/// What went wrong while converting a value
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConversionErrorKind {
+    /// The zval holds a different type, e.g. expected "int", actual "string"
+    Type { expected: Cow<'static, str>, actual: Cow<'static, str> },
+    /// Strings converted into `&str`/`String` have to be valid UTF-8
+    Utf8(str::Utf8Error),
+    /// Tuples are read from arrays with exactly `expected` elements
+    Length { expected: usize, actual: usize },
+    /// Not callable, with the reason reported by the engine ("function \"x\" not found or invalid function name")
+    Callback(String),
+    /// Anything else, e.g. a custom conversion or a rejected write
+    Custom(String)
+}
+
+/// A failed conversion, located by function, argument and the keys of nested arrays
+///
+/// Displayed like the TypeErrors of the engine:
+/// `foo(): Argument #1 ($matrix)[1][0] must be of type float, string given`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionError {
+    pub kind: ConversionErrorKind,
+    /// Keys of the nested element which failed, outermost first
+    pub path: Vec<String>,
+    /// The argument position (0 for the first argument)
+    pub arg: Option<usize>,
+    pub arg_name: Option<String>,
+    pub function: Option<String>
+}
+
+impl ConversionError {
+    pub fn new(kind: ConversionErrorKind) -> ConversionError {
+        ConversionError {
+            kind,
+            path: vec![],
+            arg: None,
+            arg_name: None,
+            function: None
+        }
+    }
+
+    /// expected/actual are PHP type names as returned by `Zval::type_name`
+    pub fn type_mismatch<E, A>(expected: E, actual: A) -> ConversionError
+        where E: Into<Cow<'static, str>>, A: Into<Cow<'static, str>> {
+        ConversionError::new(ConversionErrorKind::Type { expected: expected.into(), actual: actual.into() })
+    }
+
+    pub fn custom<S: Into<String>>(msg: S) -> ConversionError {
+        ConversionError::new(ConversionErrorKind::Custom(msg.into()))
+    }
+
+    /// The error occurred in the element `key` (keys are added from the inside out)
+    pub fn in_key<K: fmt::Display>(mut self, key: K) -> ConversionError {
+        self.path.insert(0, key.to_string());
+        self
+    }
+
+    /// The error occurred converting the argument at position `idx` (0 for the first one)
+    pub fn in_arg(mut self, idx: usize, name: &str) -> ConversionError {
+        self.arg = Some(idx);
+        self.arg_name = match name.is_empty() {
+            true => None,
+            false => Some(name.to_owned())
+        };
+        self
+    }
+
+    pub fn in_function(mut self, name: &str) -> ConversionError {
+        self.function = Some(name.to_owned());
+        self
+    }
+
+    /// Whether this is a type error (TypeError in PHP) rather than a custom failure
+    pub fn is_type_error(&self) -> bool {
+        !matches!(self.kind, ConversionErrorKind::Custom(_))
+    }
+
+    /// "Argument #1 ($name)[3][0]", empty if neither argument nor path are known
+    fn subject(&self) -> String {
+        let mut subject = String::new();
+        if let Some(idx) = self.arg {
+            subject.push_str(&format!("Argument #{}", idx + 1));
+            if let Some(ref name) = self.arg_name {
+                subject.push_str(&format!(" (${})", name));
+            }
+        }
+        for key in &self.path {
+            subject.push_str(&format!("[{}]", key));
+        }
+        subject
+    }
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref function) = self.function {
+            write!(f, "{}(): ", function)?;
+        }
+        let subject = self.subject();
+        if let ConversionErrorKind::Custom(ref msg) = self.kind {
+            return match subject.is_empty() {
+                true => write!(f, "{}", msg),
+                false => write!(f, "{}: {}", subject, msg)
+            }
+        }
+        write!(f, "{}", match subject.is_empty() {
+            true => "Value",
+            false => &subject[..]
+        })?;
+        match self.kind {
+            ConversionErrorKind::Type { ref expected, ref actual } => write!(f, " must be of type {}, {} given", expected, actual),
+            ConversionErrorKind::Utf8(ref err) => write!(f, " must be a valid UTF-8 string ({})", err),
+            ConversionErrorKind::Length { expected, actual } => write!(f, " must contain exactly {} elements, {} given", expected, actual),
+            ConversionErrorKind::Callback(ref reason) => write!(f, " must be a valid callback, {}", reason),
+            ConversionErrorKind::Custom(_) => unreachable!()
+        }
+    }
+}
+
+impl Error for ConversionError {
+    fn description(&self) -> &str {
+        match self.kind {
+            ConversionErrorKind::Type { .. } => "type mismatch",
+            ConversionErrorKind::Utf8(_) => "invalid UTF-8 string",
+            ConversionErrorKind::Length { .. } => "wrong number of elements",
+            ConversionErrorKind::Callback(_) => "invalid callback",
+            ConversionErrorKind::Custom(ref msg) => msg
+        }
+    }
+}
+
+impl From<str::Utf8Error> for ConversionError {
+    fn from(err: str::Utf8Error) -> ConversionError {
+        ConversionError::new(ConversionErrorKind::Utf8(err))
+    }
+}
+
+impl From<String> for ConversionError {
+    fn from(msg: String) -> ConversionError {
+        ConversionError::custom(msg)
+    }
+}
+
+impl<'a> From<&'a str> for ConversionError {
+    fn from(msg: &'a str) -> ConversionError {
+        ConversionError::custom(msg)
+    }
+}
+
+#[test]
+fn test_display() {
+    let err = ConversionError::type_mismatch("float", "string").in_key(0).in_key(1);
+    assert_eq!(err.to_string(), "[1][0] must be of type float, string given");
+    let err = err.in_arg(0, "matrix").in_function("foo");
+    assert_eq!(err.to_string(), "foo(): Argument #1 ($matrix)[1][0] must be of type float, string given");
+    let err = ConversionError::new(ConversionErrorKind::Length { expected: 2, actual: 1 }).in_arg(1, "");
+    assert_eq!(err.to_string(), "Argument #2 must contain exactly 2 elements, 1 given");
+    assert_eq!(ConversionError::type_mismatch("int", "null").to_string(), "Value must be of type int, null given");
+    assert_eq!(ConversionError::custom("no handler").in_function("bar").to_string(), "bar(): no handler");
+    assert!(!ConversionError::custom("no handler").is_type_error());
+    let err = ConversionError::new(ConversionErrorKind::Callback("no array or string given".to_owned())).in_arg(0, "cb");
+    assert_eq!(err.to_string(), "Argument #1 ($cb) must be a valid callback, no array or string given");
+    assert!(err.is_type_error());
+}
+
+#[test]
+fn test_compose() {
+    use types::Zval;
+    // user code can mix conversion errors with its own errors
+    fn to_int(zv: &mut Zval) -> Result<i64, Box<dyn Error>> {
+        let val: Result<i64, ConversionError> = From::from(zv);
+        Ok(val?)
+    }
+    let err = to_int(&mut Zval::new()).unwrap_err();
+    assert_eq!(err.to_string(), "Value must be of type int, null given");
+}
@@ -1,3 +1,6 @@
+pub mod error;
+pub use self::error::*;
+
 pub mod assign;
 pub use self::assign::AssignTo;
 
@@ -5,4 +8,4 @@ pub mod static_from;
 pub use self::static_from::*;
 
 pub mod conv_from;
-pub use self::conv_from::*;
\ No newline at end of file
+pub use self::conv_from::*;
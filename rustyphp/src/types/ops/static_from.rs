@@ -2,23 +2,64 @@
 //! Only allow static types for normal conversion (zval[T] -> T)
 //! Basically a string containing "1" cannot be interpreted as integer that way
 
-use std::mem;
+use std::collections::HashMap;
 use std::slice;
 use std::str;
+use php_config::*;
 use types::*;
-use zstr::{CZendString};
+use types::execute_data::ExecuteData;
+use zstr::{CZendString, ZendStr};
+
+/// Convert the argument `idx` (0 for the first one) named `name` of `function`,
+/// errors are located accordingly, e.g. "foo(): Argument #1 ($p1)[3] must be of type int, string given"
+pub fn convert_arg<'a, T>(zv: &'a mut Zval, idx: usize, name: &str, function: &str) -> Result<T, ConversionError> where Result<T, ConversionError>: From<&'a mut Zval> {
+    let ret: Result<T, ConversionError> = From::from(zv);
+    ret.map_err(|err| err.in_arg(idx, name).in_function(function))
+}
+
+/// Convert the arguments from position `first` on into `Variadic<T>` (errors name the actual position)
+///
+/// # Safety
+/// `ex` has to be valid for `'a` and the slots from `first` on must not be borrowed elsewhere.
+pub unsafe fn convert_variadic<'a, T>(ex: *mut ExecuteData, first: usize, name: &str, function: &str) -> Result<Variadic<T>, ConversionError> where Result<T, ConversionError>: From<&'a mut Zval> {
+    let count = (*ex).arg_count();
+    let mut values = Vec::with_capacity(count.saturating_sub(first));
+    for idx in first..count {
+        // every slot is a distinct zval, which lives as long as the call frame
+        let zv: &'a mut Zval = &mut *ExecuteData::arg_ptr(ex, idx);
+        values.push(convert_arg::<T>(zv, idx, name, function)?);
+    }
+    Ok(Variadic(values))
+}
+
+/// By-reference parameters (the engine passes a reference if the arginfo is marked `pass_by_ref`)
+impl<'a, T> From<&'a mut Zval> for Result<ByRef<'a, T>, ConversionError> {
+    #[inline]
+    fn from(zv: &'a mut Zval) -> Result<ByRef<'a, T>, ConversionError> {
+        ByRef::new(zv)
+    }
+}
+
+/// Anything `is_callable()` accepts: closures, "func", [$obj, 'method'], invokable objects, ...
+impl<'a> From<&'a mut Zval> for Result<Callable, ConversionError> {
+    #[inline]
+    fn from(zv: &'a mut Zval) -> Result<Callable, ConversionError> {
+        Callable::new(zv)
+    }
+}
 
 macro_rules! primitive_from_helper {
-    (long, $zv:expr, $cast_as:ty) => (Ok($zv.value.data as $cast_as))
+    (long, $zv:expr, $cast_as:ty) => (Ok($zv.value.data as $cast_as));
+    (double, $zv:expr, $cast_as:ty) => (Ok(unsafe { $zv.value.as_double() }.data as $cast_as))
 }
 macro_rules! primitive_from {
-    ($zval_from:ident => $($ty:ty),*) => {
+    ($zval_from:ident, $zvt:expr, $name:expr => $($ty:ty),*) => {
         $(
-            impl<'a> From<&'a mut Zval> for Result<$ty, String> {
-            #[inline]
-                fn from(zv: &mut Zval) -> Self {
-                    if zv.type_() != ZvalType::Long as u32 {
-                        return Err(format!("Zval Conversion: Got {} insteadof $zval_from", zv.type_()))
+            impl<'a> From<&'a Zval> for Result<$ty, ConversionError> {
+                #[inline]
+                fn from(zv: &Zval) -> Self {
+                    if zv.type_() != $zvt as u32 {
+                        return Err(ConversionError::type_mismatch($name, zv.type_name()))
                     }
                     primitive_from_helper!($zval_from, zv, $ty)
                 }
@@ -26,61 +67,216 @@ macro_rules! primitive_from {
         )*
     }
 }
-primitive_from!(long => i8, i16, i32, i64, u8, u16, u32, u64);
+primitive_from!(long, ZvalType::Long, "int" => i8, i16, i32, i64, u8, u16, u32, u64);
+primitive_from!(double, ZvalType::Double, "float" => f32, f64);
 
-impl<'a> From<&'a mut Zval> for Result<&'a mut Zval, String> {
+/// By-value conversions only read the zval, arguments (`&mut Zval`) are converted the same way
+macro_rules! from_mut_zval {
+    ($($ty:ty),*) => {
+        $(
+            impl<'a> From<&'a mut Zval> for Result<$ty, ConversionError> {
+                #[inline]
+                fn from(zv: &'a mut Zval) -> Self {
+                    let zv: &'a Zval = zv;
+                    From::from(zv)
+                }
+            }
+        )*
+    }
+}
+from_mut_zval!(i8, i16, i32, i64, u8, u16, u32, u64, f32, f64, bool, String, ZendStr, &'a str, &'a ZendArray);
+
+impl<'a> From<&'a Zval> for Result<bool, ConversionError> {
     #[inline]
-    fn from(zv: &'a mut Zval) -> Result<&'a mut Zval, String> {
+    fn from(zv: &'a Zval) -> Result<bool, ConversionError> {
+        match zv.type_() {
+            x if x == ZvalType::True as u32 => Ok(true),
+            x if x == ZvalType::False as u32 => Ok(false),
+            _ => Err(ConversionError::type_mismatch("bool", zv.type_name()))
+        }
+    }
+}
+
+impl<'a> From<&'a mut Zval> for Result<&'a mut Zval, ConversionError> {
+    #[inline]
+    fn from(zv: &'a mut Zval) -> Result<&'a mut Zval, ConversionError> {
         Ok(zv)
     }
 }
 
-impl<'a> From<&'a mut Zval> for Result<&'a mut ZendArray, String> {
+impl<'a> From<&'a Zval> for Result<&'a ZendArray, ConversionError> {
+    #[inline]
+    fn from(zv: &'a Zval) -> Result<&'a ZendArray, ConversionError> {
+        if zv.type_() != ZvalType::Array as u32 {
+            return Err(ConversionError::type_mismatch("array", zv.type_name()))
+        }
+        Ok(unsafe {
+            &*(zv.value.as_ptr().data as *const ZendArray)
+        })
+    }
+}
+
+/// Mutable access separates the array first (copy on write),
+/// so changes never show up in other variables sharing the same hashtable
+impl<'a> From<&'a mut Zval> for Result<&'a mut ZendArray, ConversionError> {
     #[inline]
-    fn from(zv: &'a mut Zval) -> Result<&'a mut ZendArray, String> {
+    fn from(zv: &'a mut Zval) -> Result<&'a mut ZendArray, ConversionError> {
         if zv.type_() != ZvalType::Array as u32 {
-            return Err(format!("Zval Conversion: Got {} insteadof array", zv.type_()))
+            return Err(ConversionError::type_mismatch("array", zv.type_name()))
         }
         Ok(unsafe {
-            mem::transmute(zv.value.as_ptr_mut().data)
+            array::separate_array(zv);
+            &mut *(zv.value.as_ptr_mut().data as *mut ZendArray)
         })
     }
 }
 
-impl<'a> From<&'a mut Zval> for Result<&'a mut ZvalValueObject, String> {
+impl<'a> From<&'a mut Zval> for Result<&'a mut ZvalValueObject, ConversionError> {
     #[inline]
-    fn from(zv: &'a mut Zval) -> Result<&'a mut ZvalValueObject, String> {
+    fn from(zv: &'a mut Zval) -> Result<&'a mut ZvalValueObject, ConversionError> {
         if zv.type_() != ZvalType::Object as u32 {
-            return Err(format!("Zval Conversion: Got {} insteadof object", zv.type_()))
+            return Err(ConversionError::type_mismatch("object", zv.type_name()))
         }
         Ok(unsafe {
-            mem::transmute(zv.value.as_ptr_mut().data)
+            &mut *(zv.value.as_ptr_mut().data as *mut ZvalValueObject)
         })
     }
 }
 
-impl<'a> From<&'a mut Zval> for Result<String, String> {
+impl<'a> From<&'a Zval> for Result<String, ConversionError> {
     #[inline]
-    fn from(zv: &'a mut Zval) -> Self {
-        let tmp: Result<&'a str, String> = From::from(zv);
+    fn from(zv: &'a Zval) -> Self {
+        let tmp: Result<&'a str, ConversionError> = From::from(zv);
         tmp.map(|st| st.to_owned())
     }
 }
 
-impl<'a> From<&'a mut Zval> for Result<&'a str, String> {
-    fn from(zv: & mut Zval) -> Self {
+impl<'a> From<&'a Zval> for Result<ZendStr, ConversionError> {
+    fn from(zv: &'a Zval) -> Self {
         if zv.type_() != ZvalType::String as u32 {
-            return Err(format!("Zval Conversion: Got {} insteadof string", zv.type_()))
+            return Err(ConversionError::type_mismatch("string", zv.type_name()))
+        }
+        Ok(unsafe { ZendStr::from_raw_addref(zv.value.as_ptr().data as *mut _) })
+    }
+}
+
+impl<'a> From<&'a Zval> for Result<&'a str, ConversionError> {
+    fn from(zv: &'a Zval) -> Self {
+        if zv.type_() != ZvalType::String as u32 {
+            return Err(ConversionError::type_mismatch("string", zv.type_name()))
         }
         let slice: &[u8] = unsafe {
-            let zs: &mut CZendString = mem::transmute(zv.value.as_ptr().data);
+            let zs = &*(zv.value.as_ptr().data as *const CZendString);
             slice::from_raw_parts(zs.value.as_ptr(), zs.header.len)
         };
         let str_ = match str::from_utf8(slice) {
             Ok(x) => x,
-            Err(err) => return Err(format!("{}", err))
+            Err(err) => return Err(From::from(err))
         };
 
         Ok(str_)
     }
-}
\ No newline at end of file
+}
+
+/// null (or a missing value) is None, anything else has to convert into T
+impl<'a, T> From<&'a mut Zval> for Result<Option<T>, ConversionError> where Result<T, ConversionError>: From<&'a mut Zval> {
+    #[inline]
+    fn from(zv: &'a mut Zval) -> Self {
+        if zv.type_() <= ZvalType::Null as u32 {
+            return Ok(None)
+        }
+        let ret: Result<T, ConversionError> = From::from(zv);
+        ret.map(Some)
+    }
+}
+
+impl<'a, T> From<&'a Zval> for Result<Option<T>, ConversionError> where Result<T, ConversionError>: From<&'a Zval> {
+    #[inline]
+    fn from(zv: &'a Zval) -> Self {
+        if zv.type_() <= ZvalType::Null as u32 {
+            return Ok(None)
+        }
+        let ret: Result<T, ConversionError> = From::from(zv);
+        ret.map(Some)
+    }
+}
+
+/// Arrays are converted into values without modifying (or separating) them,
+/// `Vec<T>`, `HashMap<String, T>` and tuples take their elements by value
+macro_rules! from_mut_zval_generic {
+    ($ty:ty => $($param:ident),+) => {
+        impl<'a, $($param),+> From<&'a mut Zval> for Result<$ty, ConversionError> where $(Result<$param, ConversionError>: From<&'a Zval>),+ {
+            #[inline]
+            fn from(zv: &'a mut Zval) -> Self {
+                let zv: &'a Zval = zv;
+                From::from(zv)
+            }
+        }
+    }
+}
+
+/// The values of an array in order (keys are ignored like array_values does)
+impl<'a, T> From<&'a Zval> for Result<Vec<T>, ConversionError> where Result<T, ConversionError>: From<&'a Zval> {
+    fn from(zv: &'a Zval) -> Self {
+        let arr: Result<&'a ZendArray, ConversionError> = From::from(zv);
+        let arr = arr?;
+        let mut ret = Vec::with_capacity(arr.len());
+        for (key, val) in arr.iter() {
+            let elem: Result<T, ConversionError> = From::from(val);
+            ret.push(elem.map_err(|err| err.in_key(key))?);
+        }
+        Ok(ret)
+    }
+}
+from_mut_zval_generic!(Vec<T> => T);
+
+/// Integer keys are converted to their string representation
+impl<'a, T> From<&'a Zval> for Result<HashMap<String, T>, ConversionError> where Result<T, ConversionError>: From<&'a Zval> {
+    fn from(zv: &'a Zval) -> Self {
+        let arr: Result<&'a ZendArray, ConversionError> = From::from(zv);
+        let arr = arr?;
+        let mut ret = HashMap::with_capacity(arr.len());
+        for (key, val) in arr.iter() {
+            let elem: Result<T, ConversionError> = From::from(val);
+            let elem = elem.map_err(|err| err.in_key(key))?;
+            let key = match key {
+                ArrayKey::Index(idx) => (idx as zend_long).to_string(),
+                ArrayKey::Str(bytes) => match str::from_utf8(bytes) {
+                    Ok(x) => x.to_owned(),
+                    Err(err) => return Err(ConversionError::from(err).in_key(key))
+                }
+            };
+            ret.insert(key, elem);
+        }
+        Ok(ret)
+    }
+}
+from_mut_zval_generic!(HashMap<String, T> => T);
+
+/// Tuples are read from lists with exactly the same amount of elements
+macro_rules! tuple_from {
+    ($len:expr => $($name:ident),+) => {
+        impl<'a, $($name),+> From<&'a Zval> for Result<($($name,)+), ConversionError> where $(Result<$name, ConversionError>: From<&'a Zval>),+ {
+            fn from(zv: &'a Zval) -> Self {
+                let arr: Result<&'a ZendArray, ConversionError> = From::from(zv);
+                let arr = arr?;
+                if arr.len() != $len {
+                    return Err(ConversionError::new(ConversionErrorKind::Length { expected: $len, actual: arr.len() }))
+                }
+                let mut values = arr.iter();
+                Ok(($({
+                    let (key, val) = values.next().unwrap();
+                    let elem: Result<$name, ConversionError> = From::from(val);
+                    elem.map_err(|err| err.in_key(key))?
+                },)+))
+            }
+        }
+        from_mut_zval_generic!(($($name,)+) => $($name),+);
+    }
+}
+tuple_from!(1 => A);
+tuple_from!(2 => A, B);
+tuple_from!(3 => A, B, C);
+tuple_from!(4 => A, B, C, D);
+tuple_from!(5 => A, B, C, D, E);
+tuple_from!(6 => A, B, C, D, E, F);
@@ -1,12 +1,20 @@
 //! ZendString
+use std::cmp;
+use std::fmt;
+use std::io;
 use std::mem;
 use std::ptr;
+use std::slice;
+use std::str;
 use super::*;
 use php_config::*;
 use zend_mm::*;
 use ffi;
 
-static IS_STR_PERSISTENT: u32 = (1<<0);
+/// GC_FLAGS of zend_string (zend_refcounted.u.v.flags)
+static IS_STR_PERSISTENT: u32 = 1<<0;
+static IS_STR_INTERNED: u32 = 1<<1;
+static GC_FLAGS_SHIFT: u32 = 8;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -23,14 +31,21 @@ pub struct CZendString {
     pub value: [c_uchar ;1]
 }
 
+unsafe impl RefcountedType for CZendString {}
+
 impl CZendString {
     pub fn new(len: usize, persistent: bool) -> Refcounted<CZendString> {
+        Refcounted::from_raw(CZendString::alloc(len, persistent))
+    }
+
+    /// zend_string_alloc: allocate a string with space for `len` bytes (+ NUL terminator)
+    fn alloc(len: usize, persistent: bool) -> *mut CZendString {
         let boxed = unsafe { zend_emalloc!(len + mem::size_of::<CZendString>(), persistent) };
-        let ptr: &mut CZendString = unsafe { mem::transmute(boxed) };
+        let ptr = unsafe { &mut *(boxed as *mut CZendString) };
 
         let mut flags = ZvalType::String as u32;
         if persistent {
-            flags |= IS_STR_PERSISTENT << 8
+            flags |= IS_STR_PERSISTENT << GC_FLAGS_SHIFT
         }
         *ptr = CZendString {
             header: CZendStringHeader {
@@ -39,19 +54,321 @@ impl CZendString {
                     type_info: flags
                 },
                 h: 0,
-                len: len,
+                len,
             },
             value: [0u8]
         };
-        Refcounted(ZendBox(ptr))
+        ptr
     }
 
+    /// Copy `val` into the string buffer, `val` has to fit into the allocated length
     #[inline]
     pub fn set_value(&mut self, val: &[u8]) {
+        assert!(val.len() <= self.header.len, "CZendString::set_value: {} bytes do not fit into a string of length {}", val.len(), self.header.len);
         unsafe {
             let dst_ptr = self.value.as_ptr() as *mut _;
-            ptr::copy_nonoverlapping(val.as_ptr(), dst_ptr, val.len() as usize);
-            *dst_ptr.offset(val.len() as isize) = 0;
+            ptr::copy_nonoverlapping(val.as_ptr(), dst_ptr, val.len());
+            *dst_ptr.add(val.len()) = 0;
+        }
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.value.as_ptr(), self.header.len) }
+    }
+
+    #[inline]
+    fn gc_flags(&self) -> u32 {
+        (self.header.refc.type_info >> GC_FLAGS_SHIFT) & 0xFF
+    }
+
+    /// Interned strings are shared for the whole process (or request) and never refcounted
+    #[inline]
+    pub fn is_interned(&self) -> bool {
+        (self.gc_flags() & IS_STR_INTERNED) == IS_STR_INTERNED
+    }
+
+    /// Persistent strings are allocated using malloc instead of the request allocator
+    #[inline]
+    pub fn is_persistent(&self) -> bool {
+        (self.gc_flags() & IS_STR_PERSISTENT) == IS_STR_PERSISTENT
+    }
+}
+
+/// An owned reference to a zend_string
+///
+/// Interned strings are never refcounted nor freed, everything else is released
+/// (zend_string_release) once the last reference is dropped.
+pub struct ZendStr(*mut CZendString);
+
+impl ZendStr {
+    /// Allocate a new request-bound string from utf8 data
+    #[inline]
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(val: &str) -> ZendStr {
+        ZendStr::from_bytes(val.as_bytes())
+    }
+
+    /// Allocate a new request-bound string (PHP strings are binary safe)
+    pub fn from_bytes(val: &[u8]) -> ZendStr {
+        ZendStr::init(val, false)
+    }
+
+    /// Allocate a string which outlives the request (e.g. for class entries or ini values)
+    pub fn persistent(val: &[u8]) -> ZendStr {
+        ZendStr::init(val, true)
+    }
+
+    fn init(val: &[u8], persistent: bool) -> ZendStr {
+        let ptr = CZendString::alloc(val.len(), persistent);
+        unsafe { (*ptr).set_value(val) };
+        ZendStr(ptr)
+    }
+
+    /// Take over one reference of an existing zend_string
+    ///
+    /// # Safety
+    /// `ptr` has to point to a valid zend_string, the reference is released on drop.
+    #[inline]
+    pub unsafe fn from_raw(ptr: *mut CZendString) -> ZendStr {
+        ZendStr(ptr)
+    }
+
+    /// Add a reference to an existing zend_string (zend_string_copy)
+    ///
+    /// # Safety
+    /// `ptr` has to point to a valid zend_string.
+    pub unsafe fn from_raw_addref(ptr: *mut CZendString) -> ZendStr {
+        if !(*ptr).is_interned() {
+            (*ptr).header.refc.refcount += 1;
+        }
+        ZendStr(ptr)
+    }
+
+    /// Release ownership of the string without touching the refcount
+    #[inline]
+    pub fn into_raw(s: ZendStr) -> *mut CZendString {
+        let ptr = s.0;
+        mem::forget(s);
+        ptr
+    }
+
+    #[inline]
+    pub fn as_ptr(&self) -> *mut CZendString {
+        self.0
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        unsafe { (*self.0).header.len }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { (*self.0).as_bytes() }
+    }
+
+    #[inline]
+    pub fn as_str(&self) -> Result<&str, str::Utf8Error> {
+        str::from_utf8(self.as_bytes())
+    }
+
+    #[inline]
+    pub fn is_interned(&self) -> bool {
+        unsafe { (*self.0).is_interned() }
+    }
+
+    #[inline]
+    pub fn is_persistent(&self) -> bool {
+        unsafe { (*self.0).is_persistent() }
+    }
+
+    /// zend_string_hash_val: the hash is computed on first use and cached in the string
+    pub fn hash(&self) -> zend_ulong {
+        unsafe {
+            let header = &mut (*self.0).header;
+            if header.h == 0 {
+                header.h = ffi::zend_hash_func(self.as_bytes().as_ptr() as *const _, header.len);
+            }
+            header.h
+        }
+    }
+
+    /// Replace the string by its interned version (zend_new_interned_string),
+    /// equal strings then share the same memory and compare by pointer
+    pub fn intern(self) -> ZendStr {
+        if self.is_interned() {
+            return self
+        }
+        // zend_new_interned_string consumes our reference
+        unsafe { ZendStr((ffi::zend_new_interned_string)(ZendStr::into_raw(self))) }
+    }
+}
+
+impl Clone for ZendStr {
+    fn clone(&self) -> ZendStr {
+        unsafe { ZendStr::from_raw_addref(self.0) }
+    }
+}
+
+/// zend_string_release
+impl Drop for ZendStr {
+    fn drop(&mut self) {
+        unsafe {
+            let zs = &mut *self.0;
+            if zs.is_interned() {
+                return;
+            }
+            zs.header.refc.refcount -= 1;
+            if zs.header.refc.refcount == 0 {
+                if zs.is_persistent() {
+                    ::libc::free(self.0 as *mut _);
+                } else {
+                    zend_free!(self.0 as *mut _);
+                }
+            }
+        }
+    }
+}
+
+impl PartialEq for ZendStr {
+    fn eq(&self, other: &ZendStr) -> bool {
+        self.0 == other.0 || self.as_bytes() == other.as_bytes()
+    }
+}
+
+impl fmt::Debug for ZendStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&String::from_utf8_lossy(self.as_bytes()), f)
+    }
+}
+
+/// Builds a zend_string in place (like smart_str), so large results do not need
+/// an intermediate Rust `String` which is copied into the zval afterwards
+///
+/// ```ignore
+/// let mut builder = ZendStringBuilder::new();
+/// write!(builder, "{}", 42).unwrap();
+/// let result: ZendStr = builder.finish();
+/// ```
+pub struct ZendStringBuilder {
+    ptr: *mut CZendString,
+    len: usize,
+    cap: usize
+}
+
+/// Initial capacity (smart_str uses 256 bytes including the header)
+static BUILDER_MIN_CAPACITY: usize = 256 - 32;
+
+impl ZendStringBuilder {
+    #[inline]
+    pub fn new() -> ZendStringBuilder {
+        ZendStringBuilder::with_capacity(BUILDER_MIN_CAPACITY)
+    }
+
+    pub fn with_capacity(cap: usize) -> ZendStringBuilder {
+        ZendStringBuilder {
+            ptr: CZendString::alloc(cap, false),
+            len: 0,
+            cap
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Ensure there is space for at least `additional` more bytes (grows via erealloc)
+    pub fn reserve(&mut self, additional: usize) {
+        let required = self.len.checked_add(additional).expect("ZendStringBuilder: capacity overflow");
+        if required <= self.cap {
+            return;
         }
+        let new_cap = cmp::max(self.cap.saturating_mul(2), required);
+        unsafe {
+            self.ptr = zend_erealloc!(self.ptr as *mut _, new_cap + mem::size_of::<CZendString>()) as *mut _;
+        }
+        self.cap = new_cap;
+    }
+
+    pub fn push_bytes(&mut self, val: &[u8]) {
+        self.reserve(val.len());
+        unsafe {
+            let dst_ptr = ((*self.ptr).value.as_ptr() as *mut u8).add(self.len);
+            ptr::copy_nonoverlapping(val.as_ptr(), dst_ptr, val.len());
+        }
+        self.len += val.len();
+    }
+
+    #[inline]
+    pub fn push_str(&mut self, val: &str) {
+        self.push_bytes(val.as_bytes())
+    }
+
+    /// The bytes written so far
+    #[inline]
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts((*self.ptr).value.as_ptr(), self.len) }
+    }
+
+    /// Terminate the string and hand it out without copying
+    pub fn finish(self) -> ZendStr {
+        let ptr = self.ptr;
+        let len = self.len;
+        mem::forget(self);
+        unsafe {
+            (*ptr).header.len = len;
+            *((*ptr).value.as_ptr() as *mut u8).add(len) = 0;
+            ZendStr::from_raw(ptr)
+        }
+    }
+}
+
+impl Default for ZendStringBuilder {
+    fn default() -> ZendStringBuilder {
+        ZendStringBuilder::new()
+    }
+}
+
+impl Drop for ZendStringBuilder {
+    fn drop(&mut self) {
+        unsafe { zend_free!(self.ptr as *mut _); }
+    }
+}
+
+impl fmt::Write for ZendStringBuilder {
+    #[inline]
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push_str(s);
+        Ok(())
+    }
+}
+
+impl io::Write for ZendStringBuilder {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.push_bytes(buf);
+        Ok(buf.len())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
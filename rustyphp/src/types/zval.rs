@@ -1,28 +1,29 @@
 use zend_mm::*;
 use php_config::*;
 use types::*;
-use ::ffi;
+use exception::{self, PhpException, ExceptionClass};
 
-use std::mem;
 use std::ops::{Deref, DerefMut};
 use std::ptr;
 
 /// zval.u1.v.type_flags
-static IS_TYPE_CONSTANT: u32 = (1<<0);
-static IS_TYPE_IMMUTABLE: u32 = (1<<1);
-static IS_TYPE_REFCOUNTED: u32 = (1<<2);
-static IS_TYPE_COLLECTABLE: u32 = (1<<3);
-static IS_TYPE_COPYABLE: u32 = (1<<4);
+static IS_TYPE_REFCOUNTED: u32 = 1<<2;
+static IS_TYPE_COLLECTABLE: u32 = 1<<3;
+static IS_TYPE_COPYABLE: u32 = 1<<4;
 static Z_TYPE_FLAGS_SHIFT: u32 = 8;
 
 macro_rules! union {
     ($base:ident, $variant:ident, $variant_mut:ident, $otherty:ty) => {
         impl $base {
+            /// # Safety
+            /// The zval type has to match the union member which is read.
             #[inline]
             pub unsafe fn $variant(&self) -> &$otherty {
                 ::std::mem::transmute(self)
             }
 
+            /// # Safety
+            /// The zval type has to match the union member which is written.
             #[inline]
             pub unsafe fn $variant_mut(&mut self) -> &mut $otherty {
                 ::std::mem::transmute(self)
@@ -64,8 +65,8 @@ struct ZendObjectHandlers {
     dtor_obj: *mut c_void,
     clone_obj: *mut c_void,
     /* individual object functions */
-    read_property: extern fn (obj: *mut Zval, member: *mut Zval, ty: c_int, cache_slot: *mut *mut c_void, rv: *mut Zval) -> *mut Zval,
-    write_property: extern fn(obj: *mut Zval, member: *mut Zval, val: *mut Zval, cache_slot: *mut *mut c_void) -> *mut Zval,
+    read_property: extern "C" fn(obj: *mut Zval, member: *mut Zval, ty: c_int, cache_slot: *mut *mut c_void, rv: *mut Zval) -> *mut Zval,
+    write_property: extern "C" fn(obj: *mut Zval, member: *mut Zval, val: *mut Zval, cache_slot: *mut *mut c_void) -> *mut Zval,
     read_dimension: *mut c_void,
     write_dimension: *mut c_void,
     get_property_ptr_ptr: *mut c_void,
@@ -101,9 +102,11 @@ pub struct ZvalValueObject {
     prop_table: [Zval; 1]
 }
 
+unsafe impl RefcountedType for ZvalValueObject {}
+
 impl<'a> ZvalValueObject {
     /// Read a property from the object
-    pub fn read_property<T>(&mut self, name: &str) -> Result<T, String> where Result<T, String>: From<&'a mut Zval> {
+    pub fn read_property<T>(&mut self, name: &str) -> Result<T, PhpException> where Result<T, ConversionError>: From<&'a mut Zval> {
         let mut member = ZvalGuard(Zval::new());
         name.assign_to(&mut member); //@alloc member
         // Zval for call handler (as obj ptr) (maybe cache it?)
@@ -115,19 +118,21 @@ impl<'a> ZvalValueObject {
         let value: &mut Zval;
         unsafe {
             if self.obj_handlers.is_null() {
-                return Err(format!("read_property: object handler is null"))
+                return Err(PhpException::new("read_property: object handler is null").class(ExceptionClass::Error))
             }
             let handler_read_property = (*self.obj_handlers).read_property;
+            let (obj, member, zv) = (&mut obj as *mut Zval, &mut *member as *mut Zval, &mut zv as *mut Zval);
+            // __get() might throw or bail out
             // Using cache_slot and the underlying caching does virtually not bring a huge speed advantage
-            value = mem::transmute(handler_read_property(&mut obj as *mut _, &mut member as &mut Zval, 0, ptr::null_mut(), &mut zv as *mut _));
+            value = &mut *exception::try_catch(|| handler_read_property(obj, member, 0, ptr::null_mut(), zv))?;
         };
 
-        // Err("test".to_owned())
-        From::from(value)
+        let ret: Result<T, ConversionError> = From::from(value);
+        ret.map_err(From::from)
     }
 
     /// Assign a value to an object property
-    pub fn write_property<T: AssignTo>(&mut self, name: &str, value: T) -> Option<String> {
+    pub fn write_property<T: AssignTo>(&mut self, name: &str, value: T) -> Option<PhpException> {
         let mut member = ZvalGuard(Zval::new());
         name.assign_to(&mut member); //@alloc member
         let mut tmp = Zval::new();
@@ -137,12 +142,13 @@ impl<'a> ZvalValueObject {
         self.assign_to(&mut obj);
         unsafe {
             if self.obj_handlers.is_null() {
-                return Some(format!("write_property: object handler is null"))
+                return Some(PhpException::new("write_property: object handler is null").class(ExceptionClass::Error))
             }
             let handler_write_property = (*self.obj_handlers).write_property;
-            handler_write_property(&mut obj as *mut _, &mut member as &mut Zval, &mut tmp, ptr::null_mut());
-        };
-        None
+            let (obj, member, tmp) = (&mut obj as *mut Zval, &mut *member as *mut Zval, &mut tmp as *mut Zval);
+            // __set() might throw or bail out
+            exception::try_catch(|| handler_write_property(obj, member, tmp, ptr::null_mut())).err()
+        }
     }
 }
 
@@ -156,7 +162,10 @@ pub struct Zval {
 
 /// Allocation drop guard
 #[derive(Debug)]
-pub struct ZvalGuard(Zval);
+pub struct ZvalGuard(pub Zval);
+
+/// A zval owning its value (released on drop), e.g. the return value of `Callable::call`
+pub type OwnedZval = ZvalGuard;
 
 /// Ensures not to leak memory
 impl Drop for ZvalGuard {
@@ -179,11 +188,17 @@ impl Deref for ZvalGuard {
 }
 
 impl DerefMut for ZvalGuard {
-    fn deref_mut<'a>(&'a mut self) -> &'a mut Zval {
+    fn deref_mut(&mut self) -> &mut Zval {
         &mut self.0
     }
 }
 
+impl Default for Zval {
+    fn default() -> Zval {
+        Zval::new()
+    }
+}
+
 impl Zval {
     pub fn new() -> Zval {
         Zval {
@@ -203,11 +218,29 @@ impl Zval {
         (self.u1 >> 8) & 0xFF
     }
 
+    /// The PHP name of the stored type (as used in PHP's own error messages)
+    pub fn type_name(&self) -> &'static str {
+        match self.type_() {
+            0 | 1 => "null",
+            2 | 3 => "bool",
+            4 => "int",
+            5 => "float",
+            6 => "string",
+            7 => "array",
+            8 => "object",
+            9 => "resource",
+            10 => "reference",
+            _ => "unknown"
+        }
+    }
+
     #[inline]
     pub fn set_type(&mut self, type_: ZvalType) {
         self.u1 = match type_ {
             ZvalType::String => ZvalType::String as u32 | ((IS_TYPE_REFCOUNTED | IS_TYPE_COPYABLE) << Z_TYPE_FLAGS_SHIFT),
+            ZvalType::Array => ZvalType::Array as u32 | ((IS_TYPE_REFCOUNTED | IS_TYPE_COLLECTABLE | IS_TYPE_COPYABLE) << Z_TYPE_FLAGS_SHIFT),
             ZvalType::Object => ZvalType::Object as u32 | ((IS_TYPE_REFCOUNTED | IS_TYPE_COLLECTABLE) << Z_TYPE_FLAGS_SHIFT),
+            ZvalType::Reference => ZvalType::Reference as u32 | (IS_TYPE_REFCOUNTED << Z_TYPE_FLAGS_SHIFT),
             // primitives
             _ => type_ as u32
         };
@@ -224,5 +257,6 @@ pub enum ZvalType {
     Double = 5,
     String = 6,
     Array = 7,
-    Object = 8
+    Object = 8,
+    Reference = 10
 }
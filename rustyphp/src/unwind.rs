@@ -0,0 +1,142 @@
+//! Stop Rust panics at the FFI boundary
+//!
+//! Unwinding into the engine is undefined behavior, so every entry point called by PHP
+//! (generated function wrappers, module lifecycle hooks) runs user code through `guard`.
+use std::any::Any;
+use std::cell::RefCell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use exception::{self, PhpException, ExceptionClass};
+use error::{self, FatalError};
+use ffi;
+
+/// How a caught panic is reported to PHP
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PanicMode {
+    /// Throw an `Error` exception (catchable by userland code)
+    Error = 0,
+    /// Raise a fatal error (E_ERROR), aborting the request
+    Fatal = 1
+}
+
+static PANIC_MODE: AtomicUsize = AtomicUsize::new(0);
+static HOOK_INSTALLED: Once = Once::new();
+
+thread_local!(static GUARD_DEPTH: RefCell<usize> = const { RefCell::new(0) });
+thread_local!(static LAST_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) });
+
+/// Configure how panics are reported (defaults to `PanicMode::Error`)
+pub fn set_panic_mode(mode: PanicMode) {
+    PANIC_MODE.store(mode as usize, Ordering::SeqCst);
+}
+
+pub fn panic_mode() -> PanicMode {
+    match PANIC_MODE.load(Ordering::SeqCst) {
+        1 => PanicMode::Fatal,
+        _ => PanicMode::Error
+    }
+}
+
+/// Install the panic hook recording the panic location (called by `php_ext!` on MINIT),
+/// panics outside of `guard` are still reported by the previous hook
+pub fn install_hook() {
+    HOOK_INSTALLED.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !is_guarded() {
+                return prev(info);
+            }
+            let location = info.location().map(|loc| format!("{}:{}", loc.file(), loc.line()));
+            LAST_LOCATION.with(|last| *last.borrow_mut() = location);
+        }));
+    });
+}
+
+/// Run `func`, a panic is converted into a PHP error (see `PanicMode`) and `None` is returned
+pub fn guard<F: FnOnce() -> R, R>(name: &str, func: F) -> Option<R> {
+    // a bailout caught in an enclosing guard is re-raised by that guard
+    let outer_bailout = exception::take_pending_bailout();
+    GUARD_DEPTH.with(|depth| *depth.borrow_mut() += 1);
+    let ret = panic::catch_unwind(AssertUnwindSafe(func));
+    GUARD_DEPTH.with(|depth| *depth.borrow_mut() -= 1);
+    let bailout = exception::take_pending_bailout();
+    exception::restore_pending_bailout(outer_bailout);
+    let ret = match ret {
+        Ok(ret) => Some(ret),
+        Err(payload) => {
+            match payload.downcast::<FatalError>() {
+                // php_error! unwinds the Rust frames before raising the fatal error
+                Ok(fatal) => {
+                    let msg = {
+                        let FatalError(msg) = *fatal;
+                        msg
+                    };
+                    raise_fatal(msg, true);
+                },
+                Err(payload) => {
+                    let location = LAST_LOCATION.with(|last| last.borrow_mut().take());
+                    let msg = panic_message(name, &*payload, location);
+                    drop(payload);
+                    report_panic(msg);
+                }
+            }
+            None
+        }
+    };
+    // all Rust frames are gone now, so a bailout caught by `try_catch` can continue
+    if bailout {
+        exception::reraise_bailout();
+    }
+    ret
+}
+
+/// Whether the current code runs inside `guard` (called from PHP through a wrapper)
+pub fn is_guarded() -> bool {
+    GUARD_DEPTH.with(|depth| *depth.borrow() > 0)
+}
+
+/// "name(): panicked at 'msg', src/lib.rs:42"
+fn panic_message(name: &str, payload: &(dyn Any + Send), location: Option<String>) -> String {
+    let msg = match payload.downcast_ref::<&str>() {
+        Some(msg) => *msg,
+        None => match payload.downcast_ref::<String>() {
+            Some(msg) => &msg[..],
+            None => "Box<Any>"
+        }
+    };
+    match location {
+        Some(location) => format!("{}(): panicked at '{}', {}", name, msg, location),
+        None => format!("{}(): panicked at '{}'", name, msg)
+    }
+}
+
+fn report_panic(msg: String) {
+    match panic_mode() {
+        PanicMode::Error => PhpException::new(msg).class(ExceptionClass::Error).throw(),
+        PanicMode::Fatal => raise_fatal(msg, false)
+    }
+}
+
+/// Raise E_ERROR (prefixed with the active function like `error::raise`), this bails out of the request:
+/// `msg` is dropped before, the C string handed to the engine is the only value left on this frame
+fn raise_fatal(msg: String, prefixed: bool) {
+    let c_msg = error::c_message(&msg);
+    drop(msg);
+    unsafe {
+        match prefixed {
+            true => ffi::rustyphp_error(ffi::E_ERROR, c_msg.as_ptr()),
+            false => ffi::zend_error(ffi::E_ERROR, b"%s\0".as_ptr() as *const _, c_msg.as_ptr())
+        }
+    }
+}
+
+#[test]
+fn test_panic_message() {
+    let payload: Box<dyn Any + Send> = Box::new("boom");
+    assert_eq!(panic_message("f", &*payload, Some("src/lib.rs:1".to_owned())), "f(): panicked at 'boom', src/lib.rs:1");
+    let payload: Box<dyn Any + Send> = Box::new(format!("{}", 42));
+    assert_eq!(panic_message("f", &*payload, None), "f(): panicked at '42'");
+    let payload: Box<dyn Any + Send> = Box::new(42);
+    assert_eq!(panic_message("f", &*payload, None), "f(): panicked at 'Box<Any>'");
+}
@@ -1,157 +1,342 @@
 //! Zend Memory Allocation Utilities
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::io::{self, Write};
 use std::mem;
-use std::ops::{Placer, Place, InPlace, Deref, DerefMut};
+use std::ops::{Deref, DerefMut};
+use std::process;
 use std::ptr;
-use std::marker;
 use std::fmt;
 use ffi;
 
-pub const ZEND_MM: ZendMMSingleton = ZendMMSingleton { _force_singleton: () };
+/// Alignment guaranteed by emalloc (ZEND_MM_ALIGNMENT)
+pub const ZEND_MM_ALIGNMENT: usize = 8;
 
-#[derive(Copy, Clone)]
-pub struct ZendMMSingleton {
-    _force_singleton: (),
+/// A pointer type for values allocated by the Zend memory manager
+///
+/// Request-bound boxes use `emalloc`, persistent boxes `pemalloc(.., 1)` and survive requests.
+/// Types with an alignment above `ZEND_MM_ALIGNMENT` are over-allocated and aligned manually,
+/// their raw pointers can therefore not be released by the engine itself.
+pub struct ZendBox<T: ?Sized> {
+    ptr: *mut T,
+    persistent: bool
 }
 
-impl<T> Placer<T> for ZendMMSingleton {
-    type Place = ZendBoxIntermediate<T>;
+/// Allocate `size` bytes aligned to `align`, returns a dangling (aligned) pointer for size 0
+unsafe fn zend_alloc(size: usize, align: usize, persistent: bool) -> *mut u8 {
+    if size == 0 {
+        return align as *mut u8
+    }
+    let over_aligned = align > ZEND_MM_ALIGNMENT;
+    // room to align the pointer and to store the base pointer in front of it
+    let alloc_size = match over_aligned {
+        true => size.checked_add(align).expect("ZendBox: allocation size overflow"),
+        false => size
+    };
+    let base = zend_emalloc!(alloc_size, persistent) as *mut u8;
+    if base.is_null() {
+        panic!("ZendBox: allocation failure");
+    }
+    if !over_aligned {
+        return base
+    }
+    let user = align_up(base as usize + mem::size_of::<usize>(), align);
+    *((user - mem::size_of::<usize>()) as *mut usize) = base as usize;
+    user as *mut u8
+}
 
-    fn make_place(self) -> ZendBoxIntermediate<T> {
-        make_place()
+/// Release memory allocated by `zend_alloc` with the same size/alignment
+unsafe fn zend_dealloc(ptr: *mut u8, size: usize, align: usize, persistent: bool) {
+    if size == 0 {
+        return;
+    }
+    let base = match align > ZEND_MM_ALIGNMENT {
+        true => *((ptr as usize - mem::size_of::<usize>()) as *const usize) as *mut u8,
+        false => ptr
+    };
+    if persistent {
+        ::libc::free(base as *mut _);
+    } else {
+        zend_free!(base as *mut _);
     }
 }
 
-pub struct ZendBox<T>(pub *mut T);
+#[inline]
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
 
 impl<T> ZendBox<T> {
+    /// Move `val` into request-bound memory (emalloc)
     #[inline]
     pub fn new(val: T) -> ZendBox<T> {
-        let box_ = ZEND_MM <- val;
-        box_
+        ZendBox::alloc(val, false)
     }
 
+    /// Move `val` into persistent memory, which is not released at the end of the request
+    #[inline]
+    pub fn new_persistent(val: T) -> ZendBox<T> {
+        ZendBox::alloc(val, true)
+    }
+
+    fn alloc(val: T, persistent: bool) -> ZendBox<T> {
+        unsafe {
+            let ptr = zend_alloc(mem::size_of::<T>(), mem::align_of::<T>(), persistent) as *mut T;
+            ptr::write(ptr, val);
+            ZendBox { ptr, persistent }
+        }
+    }
+
+    /// Take ownership of a value allocated by emalloc
+    #[inline]
+    pub fn from_raw(ptr: *mut T) -> ZendBox<T> {
+        ZendBox { ptr, persistent: false }
+    }
+
+    /// Take ownership of a value allocated by pemalloc(.., 1)
+    #[inline]
+    pub fn from_raw_persistent(ptr: *mut T) -> ZendBox<T> {
+        ZendBox { ptr, persistent: true }
+    }
+}
+
+impl<T: ?Sized> ZendBox<T> {
     #[inline]
     pub fn into_raw(b: ZendBox<T>) -> *mut T {
-        let ptr = b.0;
+        let ptr = b.ptr;
         mem::forget(b);
         ptr
     }
-}
-
-pub struct ZendBoxIntermediate<T> {
-    ptr: *mut u8,
-    size: usize,
-    align: usize,
-    marker: marker::PhantomData<*mut T>,
-}
 
-impl<T> Place<T> for ZendBoxIntermediate<T> {
-    fn pointer(&mut self) -> *mut T {
-        self.ptr as *mut T
+    #[inline]
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
     }
 }
 
-fn make_place<T>() -> ZendBoxIntermediate<T> {
-    let size = mem::size_of::<T>();
-    let align = mem::align_of::<T>();
-
-    let p = if size == 0 {
-        0x01 as *mut u8
-    } else {
-        let p = unsafe { zend_emalloc!(size) } as *mut u8;
-        if p.is_null() {
-            panic!("ZendBoxIntermediate make_place allocation failure");
-        }
-        p
-    };
+impl<T> ZendBox<[T]> {
+    /// Move the elements of `vec` into request-bound memory
+    #[inline]
+    pub fn from_vec(vec: Vec<T>) -> ZendBox<[T]> {
+        ZendBox::slice_from_vec(vec, false)
+    }
 
-    ZendBoxIntermediate {
-        ptr: p,
-        size: size,
-        align: align,
-        marker: marker::PhantomData,
+    /// Move the elements of `vec` into persistent memory
+    #[inline]
+    pub fn from_vec_persistent(vec: Vec<T>) -> ZendBox<[T]> {
+        ZendBox::slice_from_vec(vec, true)
     }
-}
 
-impl<T> InPlace<T> for ZendBoxIntermediate<T> {
-    type Owner = ZendBox<T>;
-    unsafe fn finalize(self) -> ZendBox<T> {
-        let p = self.ptr as *mut T;
-        mem::forget(self);
-        ZendBox(p)
+    fn slice_from_vec(mut vec: Vec<T>, persistent: bool) -> ZendBox<[T]> {
+        let len = vec.len();
+        let size = mem::size_of::<T>().checked_mul(len).expect("ZendBox: allocation size overflow");
+        unsafe {
+            let data = zend_alloc(size, mem::align_of::<T>(), persistent) as *mut T;
+            ptr::copy_nonoverlapping(vec.as_ptr(), data, len);
+            // the elements are moved, only release the vec's buffer
+            vec.set_len(0);
+            ZendBox { ptr: ptr::slice_from_raw_parts_mut(data, len), persistent }
+        }
     }
 }
 
-impl<T> Drop for ZendBoxIntermediate<T> {
-    fn drop(&mut self) {
-        panic!("when does this run?");
-        if self.size > 0 {
-            //unsafe { heap::deallocate(self.ptr, self.size, self.align) }
-        }
+impl<T: Clone> ZendBox<[T]> {
+    /// Clone the elements of `slice` into request-bound memory
+    #[inline]
+    pub fn from_slice(slice: &[T]) -> ZendBox<[T]> {
+        ZendBox::from_vec(slice.to_vec())
     }
 }
 
 // ZendBox related stuff
-impl<T: fmt::Debug > fmt::Debug for ZendBox<T> {
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ZendBox<T> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         fmt::Debug::fmt(&**self, f)
     }
 }
 
-impl<T> Deref for ZendBox<T> {
+impl<T: ?Sized> Deref for ZendBox<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        unsafe { &*self.0 }
+        unsafe { &*self.ptr }
     }
 }
 
-impl<T> DerefMut for ZendBox<T> {
+impl<T: ?Sized> DerefMut for ZendBox<T> {
     fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.0 }
+        unsafe { &mut *self.ptr }
     }
 }
 
-impl<T> Drop for ZendBox<T> {
+/// Runs the destructor of the contained value before the memory is released
+impl<T: ?Sized> Drop for ZendBox<T> {
     fn drop(&mut self) {
+        // null when ownership was handed over to the engine (see `Refcounted`)
+        if self.ptr.is_null() {
+            return;
+        }
         unsafe {
-            zend_free!(self.0 as *mut _);
+            let size = mem::size_of_val(&*self.ptr);
+            let align = mem::align_of_val(&*self.ptr);
+            ptr::drop_in_place(self.ptr);
+            zend_dealloc(self.ptr as *mut u8, size, align, self.persistent);
         }
     }
 }
 
 // Refcounted Management
+/// GC_FLAGS shared by zend_string (IS_STR_INTERNED) and zend_array (IS_ARRAY_IMMUTABLE)
+static GC_NOT_REFCOUNTED: u32 = 1<<1;
+/// GC_TYPE values
+static GC_TYPE_STRING: u32 = 6;
+static GC_TYPE_ARRAY: u32 = 7;
+static GC_TYPE_OBJECT: u32 = 8;
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct ZendRefcounted {
     pub refcount: u32,
+    /// type (u8), flags (u8), gc_info (u16)
     pub type_info: u32
 }
 
+impl ZendRefcounted {
+    #[inline]
+    pub fn gc_type(&self) -> u32 {
+        self.type_info & 0x0F
+    }
+
+    #[inline]
+    pub fn gc_flags(&self) -> u32 {
+        (self.type_info >> 8) & 0xFF
+    }
+
+    /// The position in the GC root buffer (0 if not buffered)
+    #[inline]
+    pub fn gc_info(&self) -> u32 {
+        self.type_info >> 16
+    }
+
+    /// Interned strings and immutable arrays are shared without refcounting
+    #[inline]
+    pub fn is_refcounted(&self) -> bool {
+        let ty = self.gc_type();
+        if ty == GC_TYPE_STRING || ty == GC_TYPE_ARRAY {
+            return (self.gc_flags() & GC_NOT_REFCOUNTED) == 0
+        }
+        true
+    }
+
+    /// Arrays and objects may be part of reference cycles
+    #[inline]
+    pub fn is_collectable(&self) -> bool {
+        let ty = self.gc_type();
+        ty == GC_TYPE_OBJECT || (ty == GC_TYPE_ARRAY && self.is_refcounted())
+    }
+
+    /// GC_REFCOUNT++ (no-op for values which are not refcounted)
+    #[inline]
+    pub fn addref(&mut self) {
+        if self.is_refcounted() {
+            self.refcount += 1;
+        }
+    }
+
+    /// GC_REFCOUNT-- without destroying the value, returns the new refcount
+    #[inline]
+    pub fn delref(&mut self) -> u32 {
+        if self.is_refcounted() {
+            self.refcount -= 1;
+        }
+        self.refcount
+    }
+}
+
+/// Types whose memory layout starts with a zend_refcounted header
+///
+/// # Safety
+/// The type has to be `#[repr(C)]` with a `ZendRefcounted` as its first field.
+pub unsafe trait RefcountedType {}
+unsafe impl RefcountedType for ZendRefcounted {}
+
+/// A counted reference to a zend_string, zend_array, zend_object, ...
+///
+/// Cloning adds a reference, dropping releases one: the value is destroyed with the last
+/// reference, otherwise collectable values are handed to the cycle collector (gc_check_possible_root).
 #[derive(Debug)]
-pub struct Refcounted<T>(pub ZendBox<T>);
+pub struct Refcounted<T: RefcountedType>(pub ZendBox<T>);
 
 impl Refcounted<ZendRefcounted> {
     /// Construct a `Refcounted` objec and destroy it using the drop method
+    ///
+    /// # Safety
+    /// `p` has to point to a valid refcounted structure, one reference is released.
     pub unsafe fn drop_ptr(p: *mut ZendRefcounted) {
-        let obj = Refcounted(ZendBox(p));
+        let obj = Refcounted::from_raw(p);
         mem::drop(obj);
     }
 }
 
-impl<T> Refcounted<T> {
+impl<T: RefcountedType> Refcounted<T> {
+    /// The engine frees refcounted structures itself (efree), so they can't be over-aligned
     #[inline]
     pub fn new(val: T) -> Refcounted<T> {
-        let box_ = ZEND_MM <- val;
-        Refcounted(box_)
+        assert!(mem::align_of::<T>() <= ZEND_MM_ALIGNMENT, "Refcounted: alignment above ZEND_MM_ALIGNMENT");
+        Refcounted(ZendBox::new(val))
+    }
+
+    /// Take ownership of one reference to an already allocated refcounted structure
+    #[inline]
+    pub fn from_raw(ptr: *mut T) -> Refcounted<T> {
+        Refcounted(ZendBox::from_raw(ptr))
+    }
+
+    /// Take a new reference to an existing refcounted structure (addref)
+    ///
+    /// # Safety
+    /// `ptr` has to point to a valid refcounted structure.
+    #[inline]
+    pub unsafe fn from_raw_addref(ptr: *mut T) -> Refcounted<T> {
+        (*(ptr as *mut ZendRefcounted)).addref();
+        Refcounted::from_raw(ptr)
     }
 
     #[inline]
     pub fn into_raw(b: Refcounted<T>) -> *mut T {
-        let ptr = (b.0).0;
+        let ptr = (b.0).ptr;
         mem::forget(b);
         ptr
     }
+
+    #[inline]
+    pub fn header(&self) -> &ZendRefcounted {
+        unsafe { &*((self.0).ptr as *const ZendRefcounted) }
+    }
+
+    #[inline]
+    fn header_mut(&mut self) -> &mut ZendRefcounted {
+        unsafe { &mut *((self.0).ptr as *mut ZendRefcounted) }
+    }
+
+    #[inline]
+    pub fn refcount(&self) -> u32 {
+        self.header().refcount
+    }
+
+    /// Whether this is the only reference (so the value may be modified in place)
+    #[inline]
+    pub fn is_unique(&self) -> bool {
+        self.header().is_refcounted() && self.refcount() == 1
+    }
+}
+
+impl<T: RefcountedType> Clone for Refcounted<T> {
+    #[inline]
+    fn clone(&self) -> Refcounted<T> {
+        unsafe { Refcounted::from_raw_addref((self.0).ptr) }
+    }
 }
 
 // Test that into raw doesn't call drop later
@@ -159,45 +344,351 @@ impl<T> Refcounted<T> {
 fn test_into_raw() {
     let src_ptr = 0x666 as *mut u32;
     {
-        let box_ = ZendBox(src_ptr);
+        let box_ = ZendBox::from_raw(src_ptr);
         let ptr = ZendBox::into_raw(box_);
         assert_eq!(src_ptr, ptr);
     }
     {
-        let box_ = ZendBox(src_ptr);
-        let rc = Refcounted(box_);
+        let mut src = ZendRefcounted { refcount: 1, type_info: 0 };
+        let src_ptr = &mut src as *mut ZendRefcounted;
+        let rc = Refcounted::from_raw(src_ptr);
         let ptr = Refcounted::into_raw(rc);
         assert_eq!(ptr, src_ptr);
     }
 }
 
+// Cloning and dropping shared references only touches the refcount
+#[test]
+fn test_refcount() {
+    let mut src = ZendRefcounted { refcount: 1, type_info: GC_TYPE_STRING };
+    let rc = Refcounted::from_raw(&mut src as *mut ZendRefcounted);
+    {
+        let rc2 = rc.clone();
+        assert_eq!(rc2.refcount(), 2);
+        assert!(!rc.is_unique());
+    }
+    assert_eq!(rc.refcount(), 1);
+    assert!(rc.is_unique());
+    Refcounted::into_raw(rc);
+}
+
+// Interned strings and immutable arrays are never refcounted (nor freed)
+#[test]
+fn test_not_refcounted() {
+    for ty in &[GC_TYPE_STRING, GC_TYPE_ARRAY] {
+        let mut src = ZendRefcounted { refcount: 1, type_info: ty | (GC_NOT_REFCOUNTED << 8) };
+        {
+            let rc = Refcounted::from_raw(&mut src as *mut ZendRefcounted);
+            mem::drop(rc.clone());
+        }
+        assert_eq!(src.refcount, 1);
+        assert!(!src.is_collectable());
+    }
+}
+
+// Zero sized values never touch the allocator, so their drop glue can be tested standalone
+#[test]
+fn test_zst_drop() {
+    thread_local!(static DROPPED: Cell<usize> = const { Cell::new(0) });
+    struct Flag;
+    impl Drop for Flag {
+        fn drop(&mut self) {
+            DROPPED.with(|d| d.set(d.get() + 1));
+        }
+    }
+    mem::drop(ZendBox::new(Flag));
+    mem::drop(ZendBox::from_vec(vec![Flag, Flag]));
+    assert_eq!(DROPPED.with(|d| d.get()), 3);
+}
+
+#[test]
+fn test_align_up() {
+    assert_eq!(align_up(0x1008, 16), 0x1010);
+    assert_eq!(align_up(0x1010, 16), 0x1010);
+    assert_eq!(align_up(0x1001, 64), 0x1040);
+}
+
 /// Ensures not to leak memory
-impl<T> Drop for Refcounted<T> {
+impl<T: RefcountedType> Drop for Refcounted<T> {
     fn drop(&mut self) {
-        // Make sure we can access the refcounted structure
-        let rc: &mut ZendRefcounted = unsafe { mem::transmute(&mut *(self.0)) };
-        // If it's only referenced in this scope, we can kill it
-        if rc.refcount <= 1 {
-            // _zval_dtor_func is able to handle refcounted structures
-            unsafe { zend_dtor!(mem::transmute(rc)); }
-        } else {
-            rc.refcount -= 1;
+        if !(self.0).ptr.is_null() {
+            let rc = self.header_mut();
+            if !rc.is_refcounted() {
+                // shared immutable values are owned by the engine
+            } else if rc.refcount <= 1 {
+                // If it's only referenced in this scope, we can kill it
+                // _zval_dtor_func is able to handle refcounted structures
+                unsafe { zend_dtor!(rc as *mut ZendRefcounted as *mut _); }
+            } else {
+                rc.delref();
+                // the remaining references might form a garbage cycle (GC_ZVAL_CHECK_POSSIBLE_ROOT)
+                if rc.is_collectable() && rc.gc_info() == 0 {
+                    unsafe { ffi::gc_possible_root(rc) };
+                }
+            }
         }
         // do not free the underlying memory (it's freed from within zend when refcount <=1)
-        (self.0).0 = ptr::null_mut();
+        (self.0).ptr = ptr::null_mut();
     }
 }
 
-impl<T> Deref for Refcounted<T> {
+impl<T: RefcountedType> Deref for Refcounted<T> {
     type Target = T;
 
     fn deref(&self) -> &T {
-        &*self.0
+        &self.0
+    }
+}
+
+impl<T: RefcountedType> DerefMut for Refcounted<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
     }
 }
 
-impl<T> DerefMut for Refcounted<T> {
-    fn deref_mut<'a>(&'a mut self) -> &mut T {
-        &mut *self.0
+// Global allocator
+thread_local!(static IN_REQUEST: Cell<bool> = const { Cell::new(false) });
+// Nesting depth of `ZendAllocator::persistent`
+thread_local!(static PERSISTENT: Cell<usize> = const { Cell::new(0) });
+// Set while the block sets below are modified, their own memory always comes from the system allocator
+thread_local!(static BOOKKEEPING: Cell<bool> = const { Cell::new(false) });
+// The live blocks allocated by `emalloc` during the current request
+thread_local!(static REQUEST_BLOCKS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new()));
+// Blocks which outlived their request, PHP released their memory already
+thread_local!(static STALE_BLOCKS: RefCell<HashSet<usize>> = RefCell::new(HashSet::new()));
+
+/// The base pointer returned by the underlying allocator is stored in front of every block
+const ALLOC_HEADER_SIZE: usize = 8;
+
+/// Where a block was allocated
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockKind {
+    System,
+    Request,
+    /// Allocated by a request which is over, the memory must not be touched
+    Stale
+}
+
+/// Opt-in global allocator routing Rust allocations to the Zend memory manager
+///
+/// During a request `emalloc` is used, so allocations show up in `memory_get_usage()`
+/// and are bounded by `memory_limit`. Outside of requests (MINIT, MSHUTDOWN, ...) and inside
+/// `ZendAllocator::persistent` the system allocator is used.
+///
+/// Allocations which would exceed `memory_limit` fail instead of raising PHP's fatal error, so
+/// infallible allocations (`Vec::with_capacity`, ...) abort the process through `handle_alloc_error`.
+/// Use the fallible APIs (`Vec::try_reserve`, ...) for sizes controlled by PHP code. The check is
+/// conservative: it fails once a new chunk (2MB) of the Zend heap would exceed the limit.
+///
+/// Request memory: PHP reclaims the request heap at the end of the request, so values which are kept
+/// in statics have to be allocated inside `ZendAllocator::persistent`. The standard output is set up
+/// by `php_ext!` on MINIT. Debug builds report blocks which outlive their request on stderr,
+/// freeing them later is ignored.
+///
+/// ```ignore
+/// #[global_allocator]
+/// static ALLOC: rustyphp::ZendAllocator = rustyphp::ZendAllocator;
+/// ```
+pub struct ZendAllocator;
+
+impl ZendAllocator {
+    /// Called by `php_ext!` on MINIT: allocates the buffers std keeps in statics (stdout) persistently
+    pub fn module_startup() {
+        let _ = io::stdout().flush();
+    }
+
+    /// Called by `php_ext!` on RINIT
+    pub fn request_startup() {
+        IN_REQUEST.with(|in_request| in_request.set(true));
+    }
+
+    /// Called by `php_ext!` on RSHUTDOWN
+    pub fn request_shutdown() {
+        IN_REQUEST.with(|in_request| in_request.set(false));
+        let leaked = bookkeeping(|| {
+            let blocks = REQUEST_BLOCKS.with(|blocks| mem::take(&mut *blocks.borrow_mut()));
+            let leaked = blocks.len();
+            STALE_BLOCKS.with(|stale| stale.borrow_mut().extend(blocks));
+            leaked
+        });
+        if leaked > 0 && cfg!(debug_assertions) {
+            let _ = writeln!(io::stderr(), "ZendAllocator: {} allocation(s) outlive the request, use ZendAllocator::persistent for values kept in statics", leaked);
+        }
+    }
+
+    /// Run `func` with all allocations going to the system allocator, e.g. to initialize statics
+    pub fn persistent<F: FnOnce() -> R, R>(func: F) -> R {
+        struct Depth;
+        impl Drop for Depth {
+            fn drop(&mut self) {
+                PERSISTENT.with(|depth| depth.set(depth.get() - 1));
+            }
+        }
+        PERSISTENT.with(|depth| depth.set(depth.get() + 1));
+        let _depth = Depth;
+        func()
+    }
+
+    /// Whether new allocations come from the request heap
+    #[inline]
+    fn use_request_heap() -> bool {
+        // TLS might already be destroyed during thread teardown
+        IN_REQUEST.try_with(|in_request| in_request.get()).unwrap_or(false)
+            && PERSISTENT.try_with(|depth| depth.get() == 0).unwrap_or(false)
+            && !BOOKKEEPING.try_with(|active| active.get()).unwrap_or(true)
+    }
+
+    /// Where `user` was allocated, `remove` forgets request blocks (they are freed)
+    fn block_kind(user: usize, remove: bool) -> BlockKind {
+        // the sets release their own (system) memory while they are modified
+        if BOOKKEEPING.try_with(|active| active.get()).unwrap_or(true) {
+            return BlockKind::System
+        }
+        let request = REQUEST_BLOCKS.try_with(|blocks| match remove {
+            true => blocks.borrow_mut().remove(&user),
+            false => blocks.borrow().contains(&user)
+        }).unwrap_or(false);
+        if request {
+            return BlockKind::Request
+        }
+        let stale = STALE_BLOCKS.try_with(|stale| match remove {
+            true => stale.borrow_mut().remove(&user),
+            false => stale.borrow().contains(&user)
+        }).unwrap_or(false);
+        match stale {
+            true => BlockKind::Stale,
+            false => BlockKind::System
+        }
+    }
+
+    /// Track a block allocated from the request heap
+    fn track(user: usize) {
+        bookkeeping(|| {
+            // the address was reused, the stale block is gone for good
+            STALE_BLOCKS.with(|stale| {
+                let mut stale = stale.borrow_mut();
+                if !stale.is_empty() {
+                    stale.remove(&user);
+                }
+            });
+            REQUEST_BLOCKS.with(|blocks| blocks.borrow_mut().insert(user));
+        })
+    }
+}
+
+/// Run `func` with the system allocator (for the sets tracking the blocks)
+fn bookkeeping<F: FnOnce() -> R, R>(func: F) -> R {
+    BOOKKEEPING.with(|active| active.set(true));
+    let ret = func();
+    BOOKKEEPING.with(|active| active.set(false));
+    ret
+}
+
+/// Extra bytes needed to align a block of the underlying allocators (aligned to 8 bytes)
+#[inline]
+fn alloc_padding(align: usize) -> usize {
+    align.saturating_sub(ZEND_MM_ALIGNMENT)
+}
+
+unsafe impl GlobalAlloc for ZendAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = match layout.size().checked_add(alloc_padding(layout.align()) + ALLOC_HEADER_SIZE) {
+            Some(size) => size,
+            None => return ptr::null_mut()
+        };
+        let request = ZendAllocator::use_request_heap();
+        // emalloc would bail out on memory_limit, null makes Rust report the failure (handle_alloc_error)
+        let base = match request {
+            true => ffi::rustyphp_emalloc_checked(size),
+            false => ::libc::malloc(size)
+        } as *mut u8;
+        if base.is_null() {
+            return base;
+        }
+        let user = align_up(base as usize + ALLOC_HEADER_SIZE, layout.align().max(ZEND_MM_ALIGNMENT));
+        ptr::write((user - ALLOC_HEADER_SIZE) as *mut usize, base as usize);
+        if request {
+            ZendAllocator::track(user);
+        }
+        user as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        let user = ptr as usize;
+        match ZendAllocator::block_kind(user, true) {
+            BlockKind::Request => {
+                zend_free!(*((user - ALLOC_HEADER_SIZE) as *const usize) as *mut _);
+            },
+            BlockKind::System => ::libc::free(*((user - ALLOC_HEADER_SIZE) as *const usize) as *mut _),
+            // its memory was released with the request heap (and reported in debug builds)
+            BlockKind::Stale => {}
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let user = ptr as usize;
+        let kind = ZendAllocator::block_kind(user, false);
+        let size = match new_size.checked_add(ALLOC_HEADER_SIZE) {
+            Some(size) => size,
+            None => return ptr::null_mut()
+        };
+        if kind == BlockKind::Stale {
+            // the contents are gone, there is nothing to copy
+            abort_allocator(b"ZendAllocator: value resized after the request which allocated it ended\n");
+        }
+        // the block starts right after the header unless it is over-aligned, which has to be moved manually
+        if layout.align() > ZEND_MM_ALIGNMENT {
+            let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+            if !new_ptr.is_null() {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+            return new_ptr;
+        }
+        let base = *((user - ALLOC_HEADER_SIZE) as *const usize) as *mut _;
+        let new_base = match kind {
+            BlockKind::Request => ffi::rustyphp_erealloc_checked(base, size),
+            _ => ::libc::realloc(base, size)
+        } as *mut u8;
+        if new_base.is_null() {
+            return new_base;
+        }
+        ptr::write(new_base as *mut usize, new_base as usize);
+        let new_user = new_base as usize + ALLOC_HEADER_SIZE;
+        if kind == BlockKind::Request && new_user != user {
+            ZendAllocator::block_kind(user, true);
+            ZendAllocator::track(new_user);
+        }
+        new_user as *mut u8
+    }
+}
+
+/// A global allocator must not panic (unwind)
+fn abort_allocator(msg: &[u8]) -> ! {
+    let _ = io::stderr().write_all(msg);
+    process::abort()
+}
+
+/// memory_get_usage()
+#[inline]
+pub fn memory_usage() -> usize {
+    unsafe { ffi::zend_memory_usage(0) }
+}
+
+// Outside of requests the system allocator is used, which works without a running engine
+#[test]
+fn test_allocator_system() {
+    unsafe {
+        for &align in &[1, 8, 64] {
+            let layout = Layout::from_size_align(24, align).unwrap();
+            let ptr = ZendAllocator.alloc(layout);
+            assert_eq!(ptr as usize % align, 0);
+            ptr::write_bytes(ptr, 0xab, 24);
+            let ptr = ZendAllocator.realloc(ptr, layout, 4096);
+            assert_eq!(ptr as usize % align, 0);
+            assert!(::std::slice::from_raw_parts(ptr, 24).iter().all(|&b| b == 0xab));
+            assert_eq!(ZendAllocator::block_kind(ptr as usize, false), BlockKind::System);
+            ZendAllocator.dealloc(ptr, Layout::from_size_align(4096, align).unwrap());
+        }
     }
 }
@@ -20,18 +20,18 @@ pub struct ZendModuleEntry
     pub name: *const c_uchar,
     functions: *mut ZendFunctionEntry,
     // INIT_FUNC_ARGS int type, int module_number
-    pub module_startup_func: Option<extern fn(c_int, c_int) -> c_int>,
-    request_startup_func: Option<extern fn(c_int, c_int) -> c_int>,
+    pub module_startup_func: Option<extern "C" fn(c_int, c_int) -> c_int>,
+    pub request_startup_func: Option<extern "C" fn(c_int, c_int) -> c_int>,
     // SHUTDOWN_FUNC_ARGS int type, int module_number
-    module_shutdown_func: Option<extern fn(c_int, c_int) -> c_int>,
-    request_shutdown_func:  Option<extern fn(c_int, c_int) -> c_int>,
-    info_func: Option<extern fn(*mut ZendModuleEntry) -> *mut c_void>,
+    pub module_shutdown_func: Option<extern "C" fn(c_int, c_int) -> c_int>,
+    pub request_shutdown_func:  Option<extern "C" fn(c_int, c_int) -> c_int>,
+    info_func: Option<extern "C" fn(*mut ZendModuleEntry) -> *mut c_void>,
     pub version: *const c_uchar,
     globals_size: size_t,
     globals_ptr: *mut c_void,
-    globals_ctor: Option<extern fn(*mut c_void) -> *mut c_void>,
-    globals_dtor: Option<extern fn(*mut c_void) -> *mut c_void>,
-    post_deactivate_func: Option<extern fn() -> c_int>,
+    globals_ctor: Option<extern "C" fn(*mut c_void) -> *mut c_void>,
+    globals_dtor: Option<extern "C" fn(*mut c_void) -> *mut c_void>,
+    post_deactivate_func: Option<extern "C" fn() -> c_int>,
     module_started: c_int,
     ztype: c_uchar,
     handle: *mut c_void,
@@ -59,7 +59,7 @@ pub struct ZendInternalArgInfo {
 pub struct ZendFunctionEntry
 {
     pub name: *const c_uchar,
-    pub handler: Option<extern fn (&mut ExecuteData, &mut Zval) -> ()>,
+    pub handler: Option<extern "C" fn(*mut ExecuteData, *mut Zval) -> ()>,
     pub arg_info: *mut c_void,
     pub num_args: u32,
     pub flags: u32
@@ -85,9 +85,13 @@ pub struct ZendClassEntry
     reserved: [u8; 300]
 }
 
+/// The module entry of the extension, `php_ext!` fills in name, version and the lifecycle hooks
+///
+/// # Safety
+/// `funcs` has to point to a function entry table terminated by an empty entry, which lives as long as the module.
 #[inline]
 pub unsafe fn make_module(funcs: Option<*mut ZendFunctionEntry>) -> ZendModuleEntry {
-    let module = ZendModuleEntry {
+    ZendModuleEntry {
         size: mem::size_of::<ZendModuleEntry>() as u16,
         zend_api: ZEND_MODULE_API_NO,
         zend_debug: ZEND_DEBUG,
@@ -115,39 +119,93 @@ pub unsafe fn make_module(funcs: Option<*mut ZendFunctionEntry>) -> ZendModuleEn
         handle: ptr::null_mut(),
         module_number: 0,
         build_id: ZEND_MODULE_BUILD_ID.as_ptr()
-    };
-    return module;
+    }
+}
+
+/// Register an internal class extending `parent` (null for none), returns the engine's class entry
+///
+/// The entry is initialized by the engine (INIT_CLASS_ENTRY), `ZendClassEntry` only declares its leading fields.
+///
+/// # Safety
+/// Classes can only be registered during MINIT, `parent` has to be null or a valid class entry.
+pub unsafe fn define_class(name: &str, parent: *mut ZendClassEntry) -> *mut ZendClassEntry {
+    ffi::rustyphp_define_class(name.as_ptr() as *const _, name.len(), parent)
 }
 
+/// Declare the extension module (`get_module`), the functions and classes are collected
+/// from all `#[php_func]`, `#[php_cls]` and `#[php_exception]` items of the crate
 #[macro_export]
 macro_rules! php_ext {
     ( $($k:ident => $v:expr)* ) => {
-        static mut MODULE_PTR: Option<::rustyphp::ZendModuleEntry> = None;
-        static mut WRAPPED_STARTUP_FUNC: Option<extern fn(c_int, c_int) -> c_int> = None;
-        get_php_funcs!();
+        static mut MODULE_PTR: Option<$crate::ZendModuleEntry> = None;
+        static mut WRAPPED_STARTUP_FUNC: Option<extern "C" fn($crate::types::c_int, $crate::types::c_int) -> $crate::types::c_int> = None;
+        static mut WRAPPED_SHUTDOWN_FUNC: Option<extern "C" fn($crate::types::c_int, $crate::types::c_int) -> $crate::types::c_int> = None;
+        static mut WRAPPED_RINIT_FUNC: Option<extern "C" fn($crate::types::c_int, $crate::types::c_int) -> $crate::types::c_int> = None;
+        static mut WRAPPED_RSHUTDOWN_FUNC: Option<extern "C" fn($crate::types::c_int, $crate::types::c_int) -> $crate::types::c_int> = None;
 
-        extern fn startup_wrapper(ty: c_int, module_number: c_int) -> c_int {
-            unsafe {
-                // register classes
-                get_php_classes!();
+        extern "C" fn startup_wrapper(ty: $crate::types::c_int, module_number: $crate::types::c_int) -> $crate::types::c_int {
+            $crate::unwind::install_hook();
+            $crate::ZendAllocator::module_startup();
+            // a panic in a lifecycle hook fails it (-1 = FAILURE)
+            $crate::unwind::guard("MINIT", || unsafe {
+                if let Err(msg) = $crate::registry::check_functions().and_then(|_| $crate::registry::register_classes()) {
+                    $crate::error::raise_unprefixed($crate::error::ErrorLevel::CoreWarning, &msg);
+                    return -1
+                }
                 match WRAPPED_STARTUP_FUNC {
                     Some(func) => func(ty, module_number),
                     _ => 0
                 }
-            };
-            1
+            }).unwrap_or(-1)
+        }
+
+        extern "C" fn shutdown_wrapper(ty: $crate::types::c_int, module_number: $crate::types::c_int) -> $crate::types::c_int {
+            $crate::unwind::guard("MSHUTDOWN", || unsafe {
+                match WRAPPED_SHUTDOWN_FUNC {
+                    Some(func) => func(ty, module_number),
+                    _ => 0
+                }
+            }).unwrap_or(-1)
+        }
+
+        extern "C" fn request_startup_wrapper(ty: $crate::types::c_int, module_number: $crate::types::c_int) -> $crate::types::c_int {
+            // requests are tracked for the allocator, so request memory is never freed late
+            $crate::ZendAllocator::request_startup();
+            $crate::unwind::guard("RINIT", || unsafe {
+                match WRAPPED_RINIT_FUNC {
+                    Some(func) => func(ty, module_number),
+                    _ => 0
+                }
+            }).unwrap_or(-1)
+        }
+
+        extern "C" fn request_shutdown_wrapper(ty: $crate::types::c_int, module_number: $crate::types::c_int) -> $crate::types::c_int {
+            let ret = $crate::unwind::guard("RSHUTDOWN", || unsafe {
+                match WRAPPED_RSHUTDOWN_FUNC {
+                    Some(func) => func(ty, module_number),
+                    _ => 0
+                }
+            }).unwrap_or(-1);
+            $crate::ZendAllocator::request_shutdown();
+            ret
         }
 
         #[no_mangle]
-        pub unsafe extern fn get_module() -> *mut ::rustyphp::types::c_void {
+        pub unsafe extern "C" fn get_module() -> *mut $crate::types::c_void {
             if MODULE_PTR.is_none() {
-                let mut module = rustyphp::make_module(Some(FUNC_PTR.as_mut_ptr()));
+                let mut module = $crate::make_module(Some($crate::registry::function_entries()));
                 $(
                     module.$k = $v;
                 )*
                 // wrap the module startup func since we need it
                 WRAPPED_STARTUP_FUNC = module.module_startup_func;
                 module.module_startup_func = Some(startup_wrapper);
+                WRAPPED_SHUTDOWN_FUNC = module.module_shutdown_func;
+                module.module_shutdown_func = Some(shutdown_wrapper);
+                WRAPPED_RINIT_FUNC = module.request_startup_func;
+                module.request_startup_func = Some(request_startup_wrapper);
+                WRAPPED_RSHUTDOWN_FUNC = module.request_shutdown_func;
+                module.request_shutdown_func = Some(request_shutdown_wrapper);
 
                 assert!(module.name != ::std::ptr::null_mut(), "Extension name cannot be null");
                 assert!(module.version != ::std::ptr::null_mut(), "Extension version cannot be null");